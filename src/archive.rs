@@ -0,0 +1,406 @@
+/* Unpack compressed and archived guest OS images
+ *
+ * Guest kernels are often published compressed with gzip, xz, or zstd, or
+ * bundled inside a tar archive, which may itself be compressed. A guest can
+ * declare its format, or leave it to be detected from the download's magic
+ * bytes, and name the member to extract from a tar archive. Downloads are
+ * unpacked from file to file a chunk at a time, so they're never held in memory.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::fs::File;
+
+use flate2::read::MultiGzDecoder;
+use xz2::read::XzDecoder;
+use tar::{Archive, EntryType};
+
+use super::digest::{Digests, HashingWriter};
+
+/* supported compression schemes */
+#[derive(Clone, Copy, PartialEq)]
+enum Compression
+{
+    None,
+    Gzip,
+    Xz,
+    Zstd
+}
+
+/* magic bytes that identify each compression scheme */
+static GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+static XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+static ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/* tar archives are identified by this at this offset into the file */
+static TAR_MAGIC: &[u8] = b"ustar";
+static TAR_MAGIC_OFFSET: usize = 257;
+
+/* return true if a downloaded file has to be unpacked to get at the guest kernel image inside it
+   => path = downloaded file
+      format = declared format of the download, or None to detect it
+      member = pathname of the kernel image inside a tar archive, if any
+      label = guest's label, for error messages
+   <= returns true if the file needs unpacking, false if it's the kernel image itself, or an error message */
+pub fn needs_unpacking(path: &PathBuf, format: &Option<String>, member: &Option<String>, label: &String) -> Result<bool, String>
+{
+    let (compression, tar) = identify(path, format, member, label)?;
+    Ok(compression != Compression::None || tar == true)
+}
+
+/* turn a downloaded file into the guest kernel image it contains
+   => src = downloaded file
+      dest = pathname to write the guest kernel image
+      format = declared format of the download: raw, gz, xz, zst, tar, tar.gz, tar.xz, or tar.zst.
+               if None, the format is detected from the download's contents
+      member = pathname of the kernel image inside a tar archive. only needed if the archive holds more than one file
+      label = guest's label, for error messages
+   <= returns the size and hashes of the guest kernel image, or an error message */
+pub fn unpack(src: &PathBuf, dest: &PathBuf, format: &Option<String>, member: &Option<String>, label: &String) -> Result<Digests, String>
+{
+    let (compression, tar) = identify(src, format, member, label)?;
+    let mut input = open(src, compression, label)?;
+
+    let mut output = match File::create(dest)
+    {
+        Ok(fh) => HashingWriter::new(fh),
+        Err(e) => return Err(format!("Can't create {} for guest {}: {}", dest.display(), label, e))
+    };
+
+    match tar
+    {
+        true => extract(input, &mut output, member, label)?,
+        false => if let Err(e) = io::copy(&mut input, &mut output)
+        {
+            return Err(format!("Can't decompress guest {}: {}", label, e));
+        }
+    }
+
+    let (_, digests) = output.finish();
+    Ok(digests)
+}
+
+/* work out how a download is packed, from its declared format or else its contents
+   <= returns its compression scheme and whether it's a tar archive, or an error message */
+fn identify(path: &PathBuf, format: &Option<String>, member: &Option<String>, label: &String) -> Result<(Compression, bool), String>
+{
+    let (compression, tar) = match format
+    {
+        Some(f) => match f.to_lowercase().as_str()
+        {
+            "raw" => (Compression::None, false),
+            "gz" | "gzip" => (Compression::Gzip, false),
+            "xz" => (Compression::Xz, false),
+            "zst" | "zstd" => (Compression::Zstd, false),
+            "tar" => (Compression::None, true),
+            "tar.gz" | "tgz" => (Compression::Gzip, true),
+            "tar.xz" | "txz" => (Compression::Xz, true),
+            "tar.zst" | "tzst" => (Compression::Zstd, true),
+            _ => return Err(format!("Unknown format {} for guest {}", f, label))
+        },
+        None =>
+        {
+            let compression = detect_compression(&peek(open(path, Compression::None, label)?, XZ_MAGIC.len(), label)?);
+            let header = peek(open(path, compression, label)?, TAR_MAGIC_OFFSET + TAR_MAGIC.len(), label)?;
+            (compression, is_tar(&header))
+        }
+    };
+
+    /* a member can only be extracted from an archive */
+    Ok((compression, tar || member.is_some()))
+}
+
+/* open a downloaded file for reading, decompressing it with the given scheme */
+fn open(path: &PathBuf, compression: Compression, label: &String) -> Result<Box<dyn Read>, String>
+{
+    let input = match File::open(path)
+    {
+        Ok(fh) => fh,
+        Err(e) => return Err(format!("Can't open {} for guest {}: {}", path.display(), label, e))
+    };
+
+    match compression
+    {
+        Compression::None => Ok(Box::new(BufReader::new(input))),
+        /* files made by concatenating compressed files hold more than one stream, and all of them are wanted */
+        Compression::Gzip => Ok(Box::new(MultiGzDecoder::new(BufReader::new(input)))),
+        Compression::Xz => Ok(Box::new(XzDecoder::new_multi_decoder(BufReader::new(input)))),
+        Compression::Zstd => match zstd::stream::read::Decoder::new(input)
+        {
+            Ok(decoder) => Ok(Box::new(decoder)),
+            Err(e) => Err(format!("Can't decompress guest {}: {}", label, e))
+        }
+    }
+}
+
+/* read up to the first length bytes from a reader, or fewer if that's all there is */
+fn peek(input: Box<dyn Read>, length: usize, label: &String) -> Result<Vec<u8>, String>
+{
+    let mut data = Vec::new();
+    match input.take(length as u64).read_to_end(&mut data)
+    {
+        Ok(_) => Ok(data),
+        Err(e) => Err(format!("Can't read download for guest {}: {}", label, e))
+    }
+}
+
+/* identify the compression scheme, if any, from the data's magic bytes */
+fn detect_compression(data: &[u8]) -> Compression
+{
+    if data.starts_with(GZIP_MAGIC) == true
+    {
+        return Compression::Gzip;
+    }
+    if data.starts_with(XZ_MAGIC) == true
+    {
+        return Compression::Xz;
+    }
+    if data.starts_with(ZSTD_MAGIC) == true
+    {
+        return Compression::Zstd;
+    }
+
+    Compression::None
+}
+
+/* return true if the data looks like a tar archive */
+fn is_tar(data: &[u8]) -> bool
+{
+    match data.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len())
+    {
+        Some(magic) => magic == TAR_MAGIC,
+        None => false
+    }
+}
+
+/* copy a file out of a tar archive into output. if no member is named, the archive must contain exactly one file */
+fn extract<W: Write>(input: Box<dyn Read>, output: &mut W, member: &Option<String>, label: &String) -> Result<(), String>
+{
+    let mut archive = Archive::new(input);
+    let entries = match archive.entries()
+    {
+        Ok(e) => e,
+        Err(e) => return Err(format!("Can't read archive for guest {}: {}", label, e))
+    };
+
+    let mut found = 0;
+    let mut files = Vec::new();
+    for entry in entries
+    {
+        let mut entry = match entry
+        {
+            Ok(e) => e,
+            Err(e) => return Err(format!("Can't read archive for guest {}: {}", label, e))
+        };
+
+        if entry.header().entry_type() != EntryType::Regular
+        {
+            continue;
+        }
+
+        let path = match entry.path()
+        {
+            Ok(p) => p.to_path_buf(),
+            Err(e) => return Err(format!("Bad pathname in archive for guest {}: {}", label, e))
+        };
+        files.push(path.display().to_string());
+
+        let wanted = match member
+        {
+            Some(m) => Path::new(m.trim_start_matches("./")) == path.strip_prefix("./").unwrap_or(&path),
+            None => true
+        };
+
+        /* carry on through the archive after the first file, if no member was named, to list what's in it */
+        if wanted == true && found == 0
+        {
+            if let Err(e) = io::copy(&mut entry, output)
+            {
+                return Err(format!("Can't extract {} from archive for guest {}: {}", path.display(), label, e));
+            }
+
+            if member.is_some() == true
+            {
+                return Ok(());
+            }
+        }
+
+        if wanted == true
+        {
+            found += 1;
+        }
+    }
+
+    match (found, member)
+    {
+        (1, _) => Ok(()),
+        (0, Some(m)) => Err(format!("Can't find {} in archive for guest {}. It contains: {}", m, label, files.join(", "))),
+        (0, None) => Err(format!("Archive for guest {} contains no files", label)),
+        (_, _) => Err(format!("Archive for guest {} contains more than one file, so a member must be chosen from: {}", label, files.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::fs::{remove_dir_all, write};
+    use flate2::{Compression as Level, write::GzEncoder};
+    use xz2::write::XzEncoder;
+    use tar::{Builder, Header};
+
+    use crate::digest;
+    use crate::testutil::scratch;
+
+    static KERNEL: &[u8] = b"not really a kernel image, but it'll do";
+
+    /* build a tar archive of the given files, and directories for those whose names end with / */
+    fn tarball(files: &[(&str, &[u8])]) -> Vec<u8>
+    {
+        let mut builder = Builder::new(Vec::new());
+        for (name, data) in files
+        {
+            let mut header = Header::new_ustar();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            if name.ends_with('/') == true
+            {
+                header.set_entry_type(EntryType::Directory);
+            }
+            header.set_cksum();
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8>
+    {
+        let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn xz(data: &[u8]) -> Vec<u8>
+    {
+        let mut encoder = XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zstd(data: &[u8]) -> Vec<u8>
+    {
+        zstd::stream::encode_all(data, 0).unwrap()
+    }
+
+    /* write data to a file in a test's scratch directory */
+    fn scratch_file(dir: &Path, name: &str, data: &[u8]) -> PathBuf
+    {
+        let path = dir.join(name);
+        write(&path, data).unwrap();
+        path
+    }
+
+    /* extract a member from an in-memory tar archive */
+    fn extract_from(archive: Vec<u8>, member: Option<&str>) -> Result<Vec<u8>, String>
+    {
+        let mut output = Vec::new();
+        extract(Box::new(io::Cursor::new(archive)), &mut output, &member.map(|m| m.to_string()), &String::from("g"))?;
+        Ok(output)
+    }
+
+    #[test]
+    fn identifies_formats_by_contents()
+    {
+        let dir = scratch("archive-identify");
+        let label = String::from("g");
+        let tar = tarball(&[("Image", KERNEL)]);
+        let cases = vec![
+            ("raw", KERNEL.to_vec(), Compression::None, false),
+            ("gz", gzip(KERNEL), Compression::Gzip, false),
+            ("xz", xz(KERNEL), Compression::Xz, false),
+            ("zst", zstd(KERNEL), Compression::Zstd, false),
+            ("tar", tar.clone(), Compression::None, true),
+            ("tar.gz", gzip(&tar), Compression::Gzip, true),
+            ("tar.xz", xz(&tar), Compression::Xz, true),
+            ("tar.zst", zstd(&tar), Compression::Zstd, true)
+        ];
+
+        for (name, data, compression, is_tar) in cases
+        {
+            let path = scratch_file(&dir, name, &data);
+
+            let (detected, tar) = identify(&path, &None, &None, &label).unwrap();
+            assert!(detected == compression && tar == is_tar, "{} misidentified", name);
+
+            /* a declared format needn't be detected */
+            let (declared, tar) = identify(&path, &Some(name.to_uppercase()), &None, &label).unwrap();
+            assert!(declared == compression && tar == is_tar, "{} misdeclared", name);
+
+            assert!(needs_unpacking(&path, &None, &None, &label).unwrap() == (name != "raw"));
+        }
+
+        let path = scratch_file(&dir, "unknown", KERNEL);
+        assert!(identify(&path, &Some(String::from("rar")), &None, &label).is_err());
+
+        /* naming a member means the file has to be an archive */
+        assert!(needs_unpacking(&path, &None, &Some(String::from("Image")), &label).unwrap() == true);
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn extracts_members_by_pathname()
+    {
+        let archive = || tarball(&[("boot/", b""), ("boot/Image", KERNEL), ("boot/System.map", b"symbols"), ("./README", b"readme")]);
+
+        assert_eq!(extract_from(archive(), Some("boot/Image")).unwrap(), KERNEL);
+        assert_eq!(extract_from(archive(), Some("./boot/Image")).unwrap(), KERNEL);
+        assert_eq!(extract_from(archive(), Some("README")).unwrap(), b"readme");
+
+        let e = extract_from(archive(), Some("Image")).err().unwrap();
+        assert!(e.contains("Can't find Image") && e.contains("boot/Image, boot/System.map"));
+
+        /* without a member, the archive must hold exactly one file, not counting directories */
+        let e = extract_from(archive(), None).err().unwrap();
+        assert!(e.contains("more than one file"));
+        assert_eq!(extract_from(tarball(&[("boot/", b""), ("boot/Image", KERNEL)]), None).unwrap(), KERNEL);
+        assert!(extract_from(tarball(&[("boot/", b"")]), None).err().unwrap().contains("no files"));
+    }
+
+    #[test]
+    fn unpacks_compressed_archives()
+    {
+        let dir = scratch("archive-unpack");
+        let src = scratch_file(&dir, "src", &xz(&tarball(&[("boot/Image", KERNEL), ("boot/System.map", b"symbols")])));
+        let dest = dir.join("dest");
+
+        let digests = unpack(&src, &dest, &None, &Some(String::from("boot/Image")), &String::from("g")).unwrap();
+        assert!(digests == digest::hash_data(KERNEL));
+        assert_eq!(std::fs::read(&dest).unwrap(), KERNEL);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn unpacks_every_stream_of_concatenated_files()
+    {
+        let dir = scratch("archive-concatenated");
+        let (first, second) = KERNEL.split_at(10);
+
+        for (name, data) in [("gz", [gzip(first), gzip(second)].concat()),
+                                 ("xz", [xz(first), xz(second)].concat()),
+                                 ("zst", [zstd(first), zstd(second)].concat())]
+        {
+            let src = scratch_file(&dir, name, &data);
+            let dest = dir.join(format!("{}.out", name));
+            let digests = unpack(&src, &dest, &None, &None, &String::from("g")).unwrap();
+            assert!(digests == digest::hash_data(KERNEL), "{} truncated", name);
+        }
+
+        let _ = remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,508 @@
+/* Shared download cache for guest OS images
+ *
+ * Guest images are stored once per user, rather than once per checkout, in a
+ * content-addressed cache directory laid out as:
+ *
+ * <cache>/objects/<sha256 of image>    = the guest image itself
+ * <cache>/urls/<sha256 of url>         = text file of "<sha256 of image> <url>" recording where the image came from
+ * <cache>/signatures/<sha256 of image> = the minisign signature of a signed guest's image
+ *
+ * Cached images are hard-linked into a guest's path where possible, or copied if not.
+ * Signed guests are cached as published, before any unpacking, along with their
+ * signatures, so that they can be checked again whenever they're used.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use std::path::{Path, PathBuf};
+use std::fs::{read_dir, read_to_string, remove_file, remove_dir_all, create_dir_all, hard_link, copy, rename};
+
+use sha2::{Digest, Sha256};
+
+use super::fetch::{write_atomically, temp_pathname};
+use super::digest::{self, Digests};
+
+#[derive(Clone)]
+pub struct DownloadCache
+{
+    dir: PathBuf
+}
+
+/* an entry in the cache's URL index */
+pub struct CacheEntry
+{
+    pub url: String,
+    pub hash: String,
+    pub object: PathBuf
+}
+
+impl DownloadCache
+{
+    pub fn new(dir: PathBuf) -> DownloadCache
+    {
+        DownloadCache { dir }
+    }
+
+    /* look up a cached image, either by its expected SHA-256 hash if known, or by the URL it was fetched from.
+       an image that no longer matches its hash is removed from the cache and ignored, as is a malformed hash
+       => url = location the image would be fetched from
+          sha256 = expected SHA-256 hash of the image, if known
+       <= returns pathname of the cached image, or None if it's not cached */
+    pub fn lookup(&self, url: &String, sha256: &Option<String>) -> Option<PathBuf>
+    {
+        let hash = match sha256
+        {
+            Some(h) => h.trim().to_lowercase(),
+            None => match read_to_string(self.url_entry(url))
+            {
+                Ok(contents) => match parse_entry(&contents)
+                {
+                    Some((hash, _)) => hash,
+                    None => return None
+                },
+                Err(_) => return None
+            }
+        };
+
+        if digest::is_sha256(&hash) == false
+        {
+            return None;
+        }
+
+        let object = self.object(&hash);
+        match digest::hash_file(&object)
+        {
+            Ok(digests) => if digests.sha256 == hash
+            {
+                Some(object)
+            }
+            else
+            {
+                let _ = remove_file(&object);
+                None
+            },
+            Err(_) => None
+        }
+    }
+
+    /* move a downloaded image into the cache
+       => url = location the image was fetched from
+          image = pathname of the image, which is moved into the cache or removed
+          digests = image's size and hashes
+       <= returns pathname of the cached image, or an error message */
+    pub fn insert(&self, url: &String, image: &PathBuf, digests: &Digests) -> Result<PathBuf, String>
+    {
+        for subdir in &["objects", "urls", "signatures"]
+        {
+            let mut dir = self.dir.clone();
+            dir.push(subdir);
+            if let Err(e) = create_dir_all(&dir)
+            {
+                let _ = remove_file(image);
+                return Err(format!("Can't create download cache directory {}: {}", dir.display(), e));
+            }
+        }
+
+        /* the cache may be on another file system, in which case the image has to be copied in */
+        let object = self.object(&digests.sha256);
+        if rename(image, &object).is_err() == true
+        {
+            let temp = temp_pathname(&object);
+            let result = digest::copy_file(image, &temp).and_then(|_| rename(&temp, &object)
+                .map_err(|e| format!("Can't move {} into place as {}: {}", temp.display(), object.display(), e)));
+            let _ = remove_file(image);
+            if let Err(e) = result
+            {
+                let _ = remove_file(&temp);
+                return Err(e);
+            }
+        }
+
+        write_atomically(&self.url_entry(url), format!("{} {}\n", digests.sha256, url).as_bytes())?;
+        Ok(object)
+    }
+
+    /* keep the signature of a signed guest's cached image
+       => object = pathname of the cached image
+          signature = contents of its minisign signature
+       <= returns Ok on success, or an error message */
+    pub fn insert_signature(&self, object: &Path, signature: &str) -> Result<(), String>
+    {
+        write_atomically(&self.signature_entry(object), signature.as_bytes())
+    }
+
+    /* look up the signature of a signed guest's cached image
+       => object = pathname of the cached image
+       <= returns the contents of its minisign signature, or None if it doesn't have one */
+    pub fn signature(&self, object: &Path) -> Option<String>
+    {
+        read_to_string(self.signature_entry(object)).ok()
+    }
+
+    /* list every entry in the cache's URL index, skipping any that can't be parsed */
+    pub fn entries(&self) -> Vec<CacheEntry>
+    {
+        let mut entries = Vec::new();
+
+        let mut dir = self.dir.clone();
+        dir.push("urls");
+        if let Ok(files) = read_dir(&dir)
+        {
+            for file in files.flatten()
+            {
+                if let Ok(contents) = read_to_string(file.path())
+                {
+                    if let Some((hash, url)) = parse_entry(&contents)
+                    {
+                        let object = self.object(&hash);
+                        entries.push(CacheEntry { url, hash, object });
+                    }
+                }
+            }
+        }
+
+        entries.sort_by(|a, b| a.url.cmp(&b.url));
+        entries
+    }
+
+    /* check every cached image still matches its hash
+       <= returns a list of error messages, one for each bad or missing image */
+    pub fn verify(&self) -> Vec<String>
+    {
+        let mut errors = Vec::new();
+
+        for entry in self.entries()
+        {
+            match digest::hash_file(&entry.object)
+            {
+                Ok(digests) => if digests.sha256 != entry.hash
+                {
+                    errors.push(format!("Cached copy of {} is corrupt ({})", entry.url, entry.object.display()));
+                },
+                Err(e) => errors.push(format!("Cached copy of {} is missing: {}", entry.url, e))
+            }
+        }
+
+        errors
+    }
+
+    /* remove corrupt images, index entries without images, and images and signatures no longer in the index.
+       or remove everything if all is true. only the cache's own subdirectories are touched, as
+       the cache directory itself may be shared with other files
+       <= returns number of files removed, or an error message */
+    pub fn prune(&self, all: bool) -> Result<usize, String>
+    {
+        if all == true
+        {
+            let count = self.entries().len();
+            for subdir in &["objects", "urls", "signatures"]
+            {
+                let mut dir = self.dir.clone();
+                dir.push(subdir);
+                if dir.exists() == true
+                {
+                    if let Err(e) = remove_dir_all(&dir)
+                    {
+                        return Err(format!("Can't remove download cache directory {}: {}", dir.display(), e));
+                    }
+                }
+            }
+            return Ok(count);
+        }
+
+        let mut removed = 0;
+        let mut wanted = Vec::new();
+
+        /* drop index entries that point at bad or missing images */
+        let mut urls = self.dir.clone();
+        urls.push("urls");
+        if let Ok(files) = read_dir(&urls)
+        {
+            for file in files.flatten()
+            {
+                let good = match read_to_string(file.path()).ok().as_deref().and_then(parse_entry)
+                {
+                    Some((hash, _)) => match digest::hash_file(&self.object(&hash))
+                    {
+                        Ok(digests) => if digests.sha256 == hash
+                        {
+                            wanted.push(hash);
+                            true
+                        }
+                        else
+                        {
+                            false
+                        },
+                        Err(_) => false
+                    },
+                    None => false
+                };
+
+                if good == false && remove_file(file.path()).is_ok() == true
+                {
+                    removed += 1;
+                }
+            }
+        }
+
+        /* then drop images nothing refers to, and their signatures */
+        for subdir in &["objects", "signatures"]
+        {
+            let mut dir = self.dir.clone();
+            dir.push(subdir);
+            if let Ok(files) = read_dir(&dir)
+            {
+                for file in files.flatten()
+                {
+                    let name = file.file_name().to_string_lossy().to_string();
+                    if wanted.contains(&name) == false && remove_file(file.path()).is_ok() == true
+                    {
+                        removed += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /* generate the pathname of a cached image from its hash, which must have been checked with digest::is_sha256() */
+    fn object(&self, hash: &String) -> PathBuf
+    {
+        let mut path = self.dir.clone();
+        path.push("objects");
+        path.push(hash);
+        path
+    }
+
+    /* generate the pathname of a cached image's signature */
+    fn signature_entry(&self, object: &Path) -> PathBuf
+    {
+        let mut path = self.dir.clone();
+        path.push("signatures");
+        if let Some(leafname) = object.file_name()
+        {
+            path.push(leafname);
+        }
+        path
+    }
+
+    /* generate the pathname of a URL's index entry */
+    fn url_entry(&self, url: &String) -> PathBuf
+    {
+        let mut path = self.dir.clone();
+        path.push("urls");
+        path.push(hex::encode(Sha256::digest(url.as_bytes())));
+        path
+    }
+}
+
+/* place a cached image at dest, hard-linking it if possible, or copying it if not
+   => object = pathname of the cached image
+      dest = pathname to place the image
+   <= returns Ok on success, or an error message */
+pub fn link(object: &PathBuf, dest: &PathBuf) -> Result<(), String>
+{
+    let temp = temp_pathname(dest);
+    let _ = remove_file(&temp);
+
+    if hard_link(object, &temp).is_err() == true
+    {
+        if let Err(e) = copy(object, &temp)
+        {
+            let _ = remove_file(&temp);
+            return Err(format!("Can't copy cached {} to {}: {}", object.display(), temp.display(), e));
+        }
+    }
+
+    match rename(&temp, dest)
+    {
+        Ok(()) => Ok(()),
+        Err(e) =>
+        {
+            let _ = remove_file(&temp);
+            Err(format!("Can't move {} into place as {}: {}", temp.display(), dest.display(), e))
+        }
+    }
+}
+
+/* decode an index entry's "<hash> <url>" contents, rejecting any with a malformed hash */
+fn parse_entry(contents: &str) -> Option<(String, String)>
+{
+    let mut fields = contents.trim().splitn(2, ' ');
+    match (fields.next(), fields.next())
+    {
+        (Some(hash), Some(url)) if digest::is_sha256(hash) == true => Some((hash.to_lowercase(), url.to_string())),
+        (_, _) => None
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::fs::{read, write};
+
+    use crate::testutil::scratch;
+
+    /* add an image with the given contents to the cache
+       <= returns its URL and the pathname of the cached image */
+    fn cached(cache: &DownloadCache, dir: &Path, name: &str, contents: &[u8]) -> (String, PathBuf)
+    {
+        let url = format!("https://example.com/{}", name);
+        let image = dir.join(name);
+        write(&image, contents).unwrap();
+        let digests = digest::hash_file(&image).unwrap();
+        let object = cache.insert(&url, &image, &digests).unwrap();
+        (url, object)
+    }
+
+    #[test]
+    fn looks_up_images_by_hash_and_url()
+    {
+        let dir = scratch("cache-lookup");
+        let cache = DownloadCache::new(dir.join("cache"));
+        let url = String::from("https://example.com/Image");
+
+        let image = dir.join("Image");
+        write(&image, b"kernel").unwrap();
+        let digests = digest::hash_file(&image).unwrap();
+        let object = cache.insert(&url, &image, &digests).unwrap();
+
+        assert!(cache.lookup(&url, &None) == Some(object.clone()));
+        assert!(cache.lookup(&String::from("https://example.com/other"), &Some(digests.sha256.to_uppercase())) == Some(object.clone()));
+        assert!(cache.lookup(&String::from("https://example.com/other"), &None).is_none());
+
+        /* a corrupt image is thrown away */
+        write(&object, b"corrupt").unwrap();
+        assert!(cache.lookup(&url, &None).is_none());
+        assert!(object.exists() == false);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn ignores_malformed_hashes()
+    {
+        let dir = scratch("cache-malformed");
+        let cache = DownloadCache::new(dir.join("cache"));
+        let url = String::from("https://example.com/Image");
+        create_dir_all(dir.join("cache").join("urls")).unwrap();
+
+        /* nothing outside the cache's objects directory is touched, however the hash is mangled */
+        let victim = dir.join("victim");
+        write(&victim, b"not an image").unwrap();
+        for hash in &["../../victim", "../victim", "victim", "/etc/passwd", &"a".repeat(63), &"g".repeat(64), &"a".repeat(65)]
+        {
+            assert!(cache.lookup(&url, &Some(hash.to_string())).is_none(), "{} accepted", hash);
+
+            write(cache.url_entry(&url), format!("{} {}\n", hash, url)).unwrap();
+            assert!(cache.lookup(&url, &None).is_none(), "{} accepted from the index", hash);
+            assert!(cache.entries().is_empty());
+        }
+        assert!(victim.exists() == true);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn verifies_cached_images()
+    {
+        let dir = scratch("cache-verify");
+        let cache = DownloadCache::new(dir.join("cache"));
+        let (_, good) = cached(&cache, &dir, "good", b"good kernel");
+        let (bad_url, bad) = cached(&cache, &dir, "bad", b"bad kernel");
+        assert!(cache.verify().is_empty());
+
+        write(&bad, b"corrupt").unwrap();
+        let errors = cache.verify();
+        assert!(errors.len() == 1 && errors[0].contains("corrupt") == true && errors[0].contains(&bad_url) == true, "{:?}", errors);
+
+        remove_file(&bad).unwrap();
+        let errors = cache.verify();
+        assert!(errors.len() == 1 && errors[0].contains("missing") == true, "{:?}", errors);
+        assert!(good.exists() == true);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn prunes_bad_and_unused_entries()
+    {
+        let dir = scratch("cache-prune");
+        let cache = DownloadCache::new(dir.join("cache"));
+        let (good_url, good) = cached(&cache, &dir, "good", b"good kernel");
+        let (bad_url, bad) = cached(&cache, &dir, "bad", b"bad kernel");
+        cache.insert_signature(&good, "good signature").unwrap();
+        write(&bad, b"corrupt").unwrap();
+
+        /* an image and a signature that nothing in the index refers to */
+        let unused = dir.join("cache").join("objects").join(digest::hash_data(b"unused").sha256);
+        write(&unused, b"unused").unwrap();
+        cache.insert_signature(&unused, "unused signature").unwrap();
+
+        /* the corrupt image and its index entry go, as do the unused image and its signature */
+        assert!(cache.prune(false).unwrap() == 4);
+        assert!(bad.exists() == false && unused.exists() == false);
+        assert!(cache.signature(&unused).is_none());
+        assert!(cache.lookup(&bad_url, &None).is_none());
+        assert!(cache.lookup(&good_url, &None) == Some(good.clone()));
+        assert!(cache.signature(&good).unwrap() == "good signature");
+        assert!(cache.prune(false).unwrap() == 0);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn prunes_everything()
+    {
+        let dir = scratch("cache-prune-all");
+        let cache = DownloadCache::new(dir.join("cache"));
+        let (url, object) = cached(&cache, &dir, "good", b"good kernel");
+        cache.insert_signature(&object, "signature").unwrap();
+        cached(&cache, &dir, "other", b"other kernel");
+
+        /* files in the cache directory that aren't the cache's own are left alone */
+        let other = dir.join("cache").join("other");
+        write(&other, b"not the cache's").unwrap();
+
+        assert!(cache.prune(true).unwrap() == 2);
+        assert!(cache.entries().is_empty());
+        assert!(cache.lookup(&url, &None).is_none());
+        for subdir in &["objects", "urls", "signatures"]
+        {
+            assert!(dir.join("cache").join(subdir).exists() == false);
+        }
+        assert!(other.exists() == true);
+
+        /* pruning an empty cache isn't an error */
+        assert!(cache.prune(true).unwrap() == 0);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn links_cached_images_into_place()
+    {
+        let dir = scratch("cache-link");
+        let cache = DownloadCache::new(dir.join("cache"));
+        let (_, object) = cached(&cache, &dir, "good", b"good kernel");
+
+        /* an existing file is replaced, and the cached image stays where it is */
+        let dest = dir.join("Image");
+        write(&dest, b"old kernel").unwrap();
+        link(&object, &dest).unwrap();
+        assert!(read(&dest).unwrap() == b"good kernel");
+        assert!(read(&object).unwrap() == b"good kernel");
+
+        /* a missing image leaves the destination alone, and no temporary file behind */
+        remove_file(&object).unwrap();
+        assert!(link(&object, &dest).is_err());
+        assert!(read(&dest).unwrap() == b"good kernel");
+        assert!(read_dir(&dir).unwrap().flatten().all(|f| f.file_name().to_string_lossy().ends_with(".part") == false));
+
+        let _ = remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,179 @@
+/* Hash guest OS images as they stream past
+ *
+ * Guests can be far bigger than is comfortable to hold in memory, so they're
+ * hashed a chunk at a time as they're downloaded, unpacked, or copied, rather
+ * than read in whole and hashed afterwards. Every guest gets its size and both
+ * its SHA-256 and SHA-512 hashes worked out, covering everything the manifest,
+ * the lockfile, the download cache, and OCI registries might want to check.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use std::io::prelude::*;
+use std::io;
+use std::path::PathBuf;
+use std::fs::File;
+
+use sha2::{Digest, Sha256, Sha512};
+
+/* size of the chunks files are read in */
+pub static CHUNK_SIZE: usize = 64 * 1024;
+
+/* a file's size and hashes, in lowercase hex */
+#[derive(Clone, PartialEq)]
+pub struct Digests
+{
+    pub size: u64,
+    pub sha256: String,
+    pub sha512: String
+}
+
+/* hash data a chunk at a time */
+pub struct Hasher
+{
+    size: u64,
+    sha256: Sha256,
+    sha512: Sha512
+}
+
+impl Hasher
+{
+    pub fn new() -> Hasher
+    {
+        Hasher { size: 0, sha256: Sha256::new(), sha512: Sha512::new() }
+    }
+
+    /* add the next chunk of data */
+    pub fn update(&mut self, data: &[u8])
+    {
+        self.size += data.len() as u64;
+        self.sha256.update(data);
+        self.sha512.update(data);
+    }
+
+    /* add everything that can be read from a reader
+       <= returns Ok when the reader runs dry, or the error that stopped it */
+    pub fn update_from<R: Read>(&mut self, reader: &mut R) -> io::Result<()>
+    {
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop
+        {
+            match reader.read(&mut buffer)
+            {
+                Ok(0) => return Ok(()),
+                Ok(n) => self.update(&buffer[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    /* return the size and hashes of all the data added */
+    pub fn finish(self) -> Digests
+    {
+        Digests
+        {
+            size: self.size,
+            sha256: hex::encode(self.sha256.finalize()),
+            sha512: hex::encode(self.sha512.finalize())
+        }
+    }
+}
+
+/* a writer that hashes everything written through it */
+pub struct HashingWriter<W: Write>
+{
+    inner: W,
+    hasher: Hasher
+}
+
+impl<W: Write> HashingWriter<W>
+{
+    pub fn new(inner: W) -> HashingWriter<W>
+    {
+        HashingWriter { inner, hasher: Hasher::new() }
+    }
+
+    /* return the underlying writer, and the size and hashes of everything written */
+    pub fn finish(self) -> (W, Digests)
+    {
+        (self.inner, self.hasher.finish())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W>
+{
+    fn write(&mut self, data: &[u8]) -> io::Result<usize>
+    {
+        let written = self.inner.write(data)?;
+        self.hasher.update(&data[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        self.inner.flush()
+    }
+}
+
+/* check a SHA-256 hash is exactly 64 hex digits, as it's used as a leafname in the download cache
+   => hash = hash to check, without any surrounding whitespace
+   <= returns true if it's well-formed */
+pub fn is_sha256(hash: &str) -> bool
+{
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/* work out the size and hashes of data already in memory */
+pub fn hash_data(data: &[u8]) -> Digests
+{
+    let mut hasher = Hasher::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
+/* work out a file's size and hashes without reading it all into memory
+   => path = pathname of file to hash
+   <= returns the file's digests, or an error message */
+pub fn hash_file(path: &PathBuf) -> Result<Digests, String>
+{
+    let mut hasher = Hasher::new();
+    match File::open(path)
+    {
+        Ok(mut fh) => match hasher.update_from(&mut fh)
+        {
+            Ok(()) => Ok(hasher.finish()),
+            Err(e) => Err(format!("Can't read {}: {}", path.display(), e))
+        },
+        Err(e) => Err(format!("Can't open {}: {}", path.display(), e))
+    }
+}
+
+/* copy a file a chunk at a time, hashing it as it goes
+   => src = pathname of file to copy
+      dest = pathname of copy to create
+   <= returns the file's digests, or an error message */
+pub fn copy_file(src: &PathBuf, dest: &PathBuf) -> Result<Digests, String>
+{
+    let mut input = match File::open(src)
+    {
+        Ok(fh) => fh,
+        Err(e) => return Err(format!("Can't open {}: {}", src.display(), e))
+    };
+
+    let mut output = match File::create(dest)
+    {
+        Ok(fh) => HashingWriter::new(fh),
+        Err(e) => return Err(format!("Can't create {}: {}", dest.display(), e))
+    };
+
+    if let Err(e) = io::copy(&mut input, &mut output)
+    {
+        return Err(format!("Can't copy {} to {}: {}", src.display(), dest.display(), e));
+    }
+
+    let (_, digests) = output.finish();
+    Ok(digests)
+}
@@ -0,0 +1,1891 @@
+/* Fetch guest OS images from the network/internet
+ *
+ * Guests are taken from the shared download cache if possible, and otherwise
+ * downloaded and added to the cache. Files are written to a temporary file
+ * alongside the destination and only renamed into place once the whole body
+ * has arrived intact and passed verification. This stops a failed or partial
+ * download from being mistaken for a valid guest on a later build.
+ *
+ * Guests are streamed to disk and hashed as they arrive, and unpacked from file
+ * to file, so memory use doesn't grow with the size of the guest.
+ *
+ * Downloads that fail for a temporary reason, such as a timeout, a dropped
+ * connection, or a server error, are retried with exponential backoff. What has
+ * been received so far is kept in a .download file next to the guest, along with
+ * the URL it came from and the server's validators for it, and an HTTP Range
+ * request is used to pick up where it left off. The request carries an If-Range
+ * header so that a remote file that has changed since is sent afresh, rather
+ * than stitched onto the end of the old one. A partial download without a
+ * validator to send, or from another URL, is thrown away and started again.
+ *
+ * A guest's url can be backed up by a list of mirrors, tried in turn until one
+ * succeeds. The manifest can also rewrite URL prefixes so that, for example,
+ * an upstream host is tried via a local mirror before the upstream itself.
+ *
+ * Guests can also be resolved from a vendor directory, holding a copy of every
+ * guest as <vendor directory>/guests/<label>, which is tried before the cache and
+ * the network. In offline mode, the network isn't used at all.
+ *
+ * Downloads of signed guests are checked against their detached signatures
+ * before they are unpacked or written anywhere. Signed guests are cached and
+ * vendored as published, along with their signatures, and are checked again
+ * each time they're taken from the cache or vendor directory.
+ *
+ * Requests can be sent through a proxy, trust extra certificate authorities,
+ * and carry bearer tokens for servers that need them. Tokens and passwords are
+ * redacted from every message the fetcher produces.
+ *
+ * Every URL, including those a server redirects to, must pass the manifest's
+ * download policy, and no download may grow beyond the policy's size limit.
+ *
+ * A URL can also be a file:// URL or a pathname relative to the manifest, in
+ * which case the file is copied, or symlinked if the guest asks for it, with
+ * the same verification as a download. Local files aren't subject to the
+ * download policy and bypass the download cache, as they can change at any time.
+ *
+ * Or a URL can be an oci:// reference to an artifact in an OCI registry, which is
+ * resolved to a layer and downloaded like any other file, and checked against the
+ * layer's digest before it's unpacked and verified.
+ *
+ * A guest that's already present can be revalidated: a HEAD request carrying the
+ * server's validators from its last download asks whether it has changed. If it
+ * hasn't, it's left alone, and otherwise it's downloaded again and replaced if it
+ * differs.
+ *
+ * In verbose mode, the progress of each download is reported as it goes.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::env;
+use std::fs::{rename, remove_file, metadata, read_to_string, File, OpenOptions};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::process;
+use std::time::Duration;
+use std::cmp::min;
+use std::collections::HashMap;
+
+use tokio::sync::Semaphore;
+use tokio::time::{delay_for, timeout};
+use reqwest::{Client, Method, RequestBuilder, StatusCode, Proxy, Certificate, Url};
+use reqwest::header::{HeaderMap, RANGE, IF_RANGE, CONTENT_RANGE};
+use reqwest::redirect;
+
+use super::{Config, Guest, verify_checksums};
+use super::cache::{self, DownloadCache};
+use super::archive;
+use super::signature;
+use super::auth::{self, Credentials};
+use super::policy::DownloadPolicy;
+use super::oci;
+use super::validators::Validators;
+use super::progress::Progress;
+use super::digest::{self, Digests, Hasher};
+
+/* defaults for when the manifest's downloads section doesn't say otherwise */
+static CONNECT_TIMEOUT: u64 = 30; /* in seconds */
+static READ_TIMEOUT: u64 = 60; /* in seconds */
+static RETRIES: usize = 3;
+static BACKOFF: u64 = 1000; /* in milliseconds, doubled after each failed attempt */
+static BACKOFF_MAX: u64 = 60 * 1000; /* in milliseconds */
+
+/* most redirects to follow for one request */
+static REDIRECTS_MAX: usize = 10;
+
+/* makes each temporary file name unique within this process */
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/* marks the start of each certificate in a PEM bundle */
+static PEM_CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----";
+
+/* describe a guest to fetch */
+pub struct FetchJob
+{
+    pub url: String,
+    pub label: String,
+    pub guest: Guest,
+    pub dest: PathBuf,
+    pub refresh: bool, /* true to skip the download cache */
+    pub revalidate: bool, /* true to replace the guest already at dest only if its remote copy has changed */
+    pub as_published: bool /* true to write a signed guest to dest as published, with its signature alongside */
+}
+
+/* what became of a guest that was fetched */
+pub struct Fetched
+{
+    pub source: String, /* URL the guest came from */
+    pub replaced: bool  /* true if the guest at dest was written, false if it was left as it was */
+}
+
+/* how a download attempt failed */
+enum Failure
+{
+    Temporary(String), /* worth trying again */
+    Permanent(String)  /* no point trying again */
+}
+
+/* everything needed to fetch guests */
+#[derive(Clone)]
+pub struct Fetcher
+{
+    client: Client,
+    cache: DownloadCache,
+    jobs: usize,
+    read_timeout: Duration,
+    retries: usize,
+    backoff: Duration,
+    rewrites: HashMap<String, String>,
+    vendor: Option<PathBuf>,
+    offline: bool,
+    keys: HashMap<String, String>,
+    credentials: Credentials,
+    policy: DownloadPolicy,
+    progress: Progress,
+    base: PathBuf,
+    verbose: bool
+}
+
+impl Fetcher
+{
+    /* create a fetcher using the manifest's download, authentication, and signature settings, if any
+       => config = manifest configuration file
+          base = directory containing the manifest configuration file
+          cache = shared download cache
+          jobs = maximum number of guests to fetch at once
+          vendor = directory of vendored guests to use before the cache and the network, if any
+          offline = true to never use the network
+          verbose = true to output progress
+       <= returns the fetcher, or an error message */
+    pub fn new(config: &Config, base: &Path, cache: DownloadCache, jobs: usize, vendor: Option<PathBuf>, offline: bool, verbose: bool) -> Result<Fetcher, String>
+    {
+        let keys = match config.signatures.as_ref().and_then(|s| s.keys.as_ref())
+        {
+            Some(k) => k.clone(),
+            None => HashMap::new()
+        };
+
+        let (connect_timeout, read_timeout, retries, backoff, rewrites) = match &config.downloads
+        {
+            Some(d) => (d.connect_timeout.unwrap_or(CONNECT_TIMEOUT), d.read_timeout.unwrap_or(READ_TIMEOUT),
+                        d.retries.unwrap_or(RETRIES), d.backoff.unwrap_or(BACKOFF),
+                        d.rewrite.clone().unwrap_or(HashMap::new())),
+            None => (CONNECT_TIMEOUT, READ_TIMEOUT, RETRIES, BACKOFF, HashMap::new())
+        };
+
+        let mut credentials = Credentials::new(&config.downloads.as_ref().and_then(|d| d.auth.clone()), base);
+        let policy = DownloadPolicy::new(&config.downloads);
+
+        /* don't let a server redirect us somewhere the policy forbids */
+        let redirect_policy = policy.clone();
+        let mut builder = Client::builder()
+            .connect_timeout(Duration::from_secs(connect_timeout))
+            .redirect(redirect::Policy::custom(move |attempt|
+            {
+                if attempt.previous().len() >= REDIRECTS_MAX
+                {
+                    return attempt.error(String::from("Too many redirects"));
+                }
+
+                match redirect_policy.check_url(attempt.url().as_str())
+                {
+                    Ok(()) => attempt.follow(),
+                    Err(e) => attempt.error(e)
+                }
+            }));
+
+        if let Some(downloads) = &config.downloads
+        {
+            /* send everything through a proxy, with its credentials taken from the environment */
+            if let Some(url) = &downloads.proxy
+            {
+                let mut proxy = match Proxy::all(url.as_str())
+                {
+                    Ok(p) => p,
+                    Err(e) => return Err(credentials.redact(format!("Bad proxy {}: {}", url, e)))
+                };
+
+                if let Some(var) = &downloads.proxy_auth_env
+                {
+                    let userpass = match env::var(var)
+                    {
+                        Ok(u) => u,
+                        Err(e) => return Err(format!("Can't read proxy credentials from environment variable {}: {}", var, e))
+                    };
+
+                    let mut fields = userpass.splitn(2, ':');
+                    let (user, password) = (fields.next().unwrap_or(""), fields.next().unwrap_or(""));
+                    credentials.add_secret(password);
+                    proxy = proxy.basic_auth(user, password);
+                }
+
+                builder = builder.proxy(proxy);
+            }
+
+            /* trust extra certificate authorities, such as a private CA */
+            if let Some(bundles) = &downloads.ca_certs
+            {
+                for bundle in bundles
+                {
+                    let path = base.join(bundle);
+                    let pem = match read_to_string(&path)
+                    {
+                        Ok(p) => p,
+                        Err(e) => return Err(format!("Can't read CA certificates {}: {}", path.display(), e))
+                    };
+
+                    for cert in pem.split(PEM_CERTIFICATE).skip(1)
+                    {
+                        match Certificate::from_pem(format!("{}{}", PEM_CERTIFICATE, cert).as_bytes())
+                        {
+                            Ok(c) => builder = builder.add_root_certificate(c),
+                            Err(e) => return Err(format!("Bad CA certificate in {}: {}", path.display(), e))
+                        }
+                    }
+                }
+            }
+        }
+
+        let client = match builder.build()
+        {
+            Ok(c) => c,
+            Err(e) => return Err(credentials.redact(format!("Can't create HTTP client: {}", e)))
+        };
+
+        Ok(Fetcher
+        {
+            client,
+            cache,
+            jobs,
+            read_timeout: Duration::from_secs(read_timeout),
+            retries,
+            backoff: Duration::from_millis(backoff),
+            rewrites,
+            vendor,
+            offline,
+            keys,
+            credentials,
+            policy,
+            progress: Progress::new(verbose),
+            base: base.to_path_buf(),
+            verbose
+        })
+    }
+
+    /* list the URLs to try, in order, to fetch a guest: its url then its mirrors,
+       each preceded by its rewritten form if one of the manifest's prefix rewrites applies
+       => url = guest's main URL
+          guest = guest's configuration
+       <= returns list of URLs to try */
+    pub fn sources(&self, url: &str, guest: &Guest) -> Vec<String>
+    {
+        let mut urls = vec![url.to_string()];
+        if let Some(mirrors) = &guest.mirrors
+        {
+            urls.extend(mirrors.iter().cloned());
+        }
+
+        let mut sources = Vec::new();
+        for url in urls
+        {
+            /* use the longest matching prefix */
+            let rewrite = self.rewrites.iter()
+                .filter(|(from, _)| url.starts_with(from.as_str()) == true)
+                .max_by_key(|(from, _)| from.len());
+
+            if let Some((from, to)) = rewrite
+            {
+                let rewritten = format!("{}{}", to, &url[from.len()..]);
+                if sources.contains(&rewritten) == false
+                {
+                    sources.push(rewritten);
+                }
+            }
+
+            if sources.contains(&url) == false
+            {
+                sources.push(url);
+            }
+        }
+
+        sources
+    }
+
+    /* fetch a collection of guests concurrently
+       => jobs = guests to fetch
+       <= returns a table of where each guest was fetched from and whether it was replaced, keyed by label,
+          and a list of error messages, one for each guest that couldn't be fetched */
+    pub async fn fetch_guests(&self, jobs: Vec<FetchJob>) -> (HashMap<String, Fetched>, Vec<String>)
+    {
+        let semaphore = Arc::new(Semaphore::new(self.jobs));
+
+        let mut tasks = Vec::new();
+        for job in jobs
+        {
+            let semaphore = semaphore.clone();
+            let fetcher = self.clone();
+            tasks.push(tokio::spawn(async move
+            {
+                let _permit = semaphore.acquire().await;
+                let result = fetcher.fetch_guest(&job).await;
+                (job.label, result)
+            }));
+        }
+
+        let mut fetched = HashMap::new();
+        let mut errors = Vec::new();
+        for task in tasks
+        {
+            match task.await
+            {
+                Ok((label, Ok(f))) =>
+                {
+                    fetched.insert(label, f);
+                },
+                Ok((_, Err(e))) => errors.push(self.credentials.redact(e)),
+                Err(e) => errors.push(format!("Guest download task failed: {}", e))
+            }
+        }
+
+        (fetched, errors)
+    }
+
+    /* fetch a guest's kernel image into dest, from the vendor directory or download cache if possible,
+       or from its URL or mirrors if not. signed guests are checked against their signatures whichever
+       way they're fetched. nothing is written to dest unless the image is obtained and passes verification
+       => job = guest to fetch, its URL, which also identifies it in the download cache, and where to write it
+       <= returns the URL the guest was fetched from and whether dest was replaced on success,
+          or an error message describing the failure */
+    pub async fn fetch_guest(&self, job: &FetchJob) -> Result<Fetched, String>
+    {
+        let (url, label, guest, dest) = (&job.url, &job.label, &job.guest, &job.dest);
+
+        /* guests being fetched afresh or checked for changes are wanted from their URLs, not from the vendor directory */
+        let vendor = match job.refresh || job.revalidate
+        {
+            true => None,
+            false => vendored_pathname(&self.vendor, label)
+        };
+
+        if let Some(vendored) = vendor
+        {
+            if vendored.exists() == true
+            {
+                if self.verbose == true
+                {
+                    self.report(format!("Using vendored copy of guest OS {}...", &guest.description));
+                }
+
+                /* signed guests are vendored as published, with their signatures */
+                if guest.signature.is_some() == true
+                {
+                    let sig_path = signature_pathname(&vendored);
+                    let signature = match read_to_string(&sig_path)
+                    {
+                        Ok(s) => s,
+                        Err(e) => return Err(format!("Can't read vendored signature {} for {}: {}", sig_path.display(), label, e))
+                    };
+
+                    let replaced = match self.check_signature(&vendored, &signature, label, guest)
+                                             .and_then(|_| self.place_signed(job, &vendored, &signature))
+                    {
+                        Ok(r) => r,
+                        Err(e) => return Err(format!("Vendored {} failed verification: {}", vendored.display(), e))
+                    };
+
+                    Validators::remove(dest);
+                    return Ok(Fetched { source: url.clone(), replaced });
+                }
+
+                let digests = digest::hash_file(&vendored)?;
+                if let Err(e) = verify_checksums(label, guest, &digests)
+                {
+                    return Err(format!("Vendored {} failed verification: {}", vendored.display(), e));
+                }
+
+                cache::link(&vendored, dest)?;
+                Validators::remove(dest);
+                return Ok(Fetched { source: url.clone(), replaced: true });
+            }
+        }
+
+        /* local files are used as they are now, not as they were when they were cached */
+        let local = self.local_path(url).is_some();
+        if let (true, Some(true), false) = (local, guest.symlink, job.as_published)
+        {
+            let source = self.symlink_guest(url, label, guest, dest).await?;
+            return Ok(Fetched { source, replaced: true });
+        }
+
+        /* ask the server whether a guest that's already present has changed */
+        if job.revalidate == true && local == false
+        {
+            if let Some(source) = self.unchanged(label, dest).await?
+            {
+                if self.verbose == true
+                {
+                    self.report(format!("Guest OS {} is up to date", &guest.description));
+                }
+                return Ok(Fetched { source, replaced: false });
+            }
+        }
+
+        if let Some(location) = &guest.signature
+        {
+            return self.fetch_signed(job, location, local).await;
+        }
+
+        let cached = match (job.refresh || job.revalidate, local)
+        {
+            (false, false) => self.cache.lookup(&cache_key(url, guest), &guest.sha256),
+            (_, _) => None
+        };
+
+        let (object, source) = match cached
+        {
+            Some(object) =>
+            {
+                if self.verbose == true
+                {
+                    self.report(format!("Using cached copy of guest OS {}...", &guest.description));
+                }
+                (object, url.clone())
+            },
+            None =>
+            {
+                if self.offline == true && local == false
+                {
+                    return Err(format!("Guest {} isn't vendored or cached, and can't be downloaded while offline", label));
+                }
+
+                if self.verbose == true
+                {
+                    self.report(format!("Downloading guest OS {}...", &guest.description));
+                }
+
+                let (raw, raw_digests, source, validators) = self.download(url, label, guest, dest).await?;
+                let unpacked = sibling_pathname(dest, "unpacked");
+                let (image, digests) = match self.verify_download(&raw, raw_digests, &unpacked, &source, label, guest)
+                {
+                    Ok(r) => r,
+                    Err(e) =>
+                    {
+                        let _ = remove_file(&raw);
+                        let _ = remove_file(&unpacked);
+                        return Err(e);
+                    }
+                };
+
+                let object = match local
+                {
+                    true => None,
+                    false => Some(self.cache.insert(&cache_key(url, guest), &image, &digests)?)
+                };
+
+                /* leave a revalidated guest alone if it hasn't really changed */
+                let unchanged = job.revalidate == true && digest::hash_file(dest).is_ok_and(|existing| existing == digests);
+                match (&object, unchanged)
+                {
+                    (Some(object), false) => cache::link(object, dest)?,
+                    (None, false) => if let Err(e) = rename(&image, dest)
+                    {
+                        let _ = remove_file(&image);
+                        return Err(format!("Can't move {} into place as {}: {}", image.display(), dest.display(), e));
+                    },
+                    (_, true) =>
+                    {
+                        let _ = remove_file(&image);
+                    }
+                }
+
+                match validators
+                {
+                    Some(v) => v.save(dest)?,
+                    None => Validators::remove(dest)
+                }
+                return Ok(Fetched { source, replaced: unchanged == false });
+            }
+        };
+
+        cache::link(&object, dest)?;
+        Validators::remove(dest);
+        Ok(Fetched { source, replaced: true })
+    }
+
+    /* fetch a signed guest as published, and its signature, from the download cache if possible, or else
+       download them both and keep them in the cache, then check the guest against its signature and write it to dest
+       => job = guest to fetch, its URL, which also identifies it in the download cache, and where to write it
+          location = URL, or pathname, of the guest's signature
+          local = true if the guest is a local file, which bypasses the cache
+       <= returns the URL the guest was fetched from and whether dest was replaced on success,
+          or an error message describing the failure */
+    async fn fetch_signed(&self, job: &FetchJob, location: &String, local: bool) -> Result<Fetched, String>
+    {
+        let (url, label, guest, dest) = (&job.url, &job.label, &job.guest, &job.dest);
+        let key = cache_key(url, guest);
+
+        if job.refresh == false && job.revalidate == false && local == false
+        {
+            if let Some((object, signature)) = self.cache.lookup(&key, &None).and_then(|o| self.cache.signature(&o).map(|s| (o, s)))
+            {
+                if self.verbose == true
+                {
+                    self.report(format!("Using cached copy of guest OS {}...", &guest.description));
+                }
+
+                /* a cached copy that no longer checks out, perhaps because the guest's key has changed, is fetched again */
+                match self.check_signature(&object, &signature, label, guest).and_then(|_| self.place_signed(job, &object, &signature))
+                {
+                    Ok(replaced) =>
+                    {
+                        Validators::remove(dest);
+                        return Ok(Fetched { source: url.clone(), replaced });
+                    },
+                    Err(e) => if self.verbose == true
+                    {
+                        self.report(format!("Cached copy of guest OS {} failed verification, so fetching it again: {}", &guest.description, e));
+                    }
+                }
+            }
+        }
+
+        if self.offline == true && local == false
+        {
+            return Err(format!("Guest {} isn't vendored or cached, and can't be downloaded while offline", label));
+        }
+
+        if self.verbose == true
+        {
+            self.report(format!("Downloading guest OS {}...", &guest.description));
+        }
+
+        let signature = self.fetch_signature(location, label).await?;
+        let (raw, raw_digests, source, validators) = self.download(url, label, guest, dest).await?;
+        if let Err(e) = self.check_signature(&raw, &signature, label, guest)
+        {
+            let _ = remove_file(&raw);
+            return Err(e);
+        }
+
+        /* keep the guest as published in the cache, unless it's a local file */
+        let raw = match local
+        {
+            true => raw,
+            false =>
+            {
+                let object = self.cache.insert(&key, &raw, &raw_digests)?;
+                self.cache.insert_signature(&object, &signature)?;
+                object
+            }
+        };
+
+        let result = self.place_signed(job, &raw, &signature);
+        if local == true
+        {
+            let _ = remove_file(&raw);
+        }
+
+        let replaced = result?;
+        match validators
+        {
+            Some(v) => v.save(dest)?,
+            None => Validators::remove(dest)
+        }
+        Ok(Fetched { source, replaced })
+    }
+
+    /* write a signed guest, already checked against its signature, to the job's dest: as published, with its
+       signature alongside, if the job asks for that, or else its kernel image
+       => job = guest being fetched, and where to write it
+          raw = pathname of the guest as published, which is left alone
+          signature = contents of the guest's minisign signature
+       <= returns whether dest was replaced, or an error message */
+    fn place_signed(&self, job: &FetchJob, raw: &PathBuf, signature: &String) -> Result<bool, String>
+    {
+        let (label, guest, dest) = (&job.label, &job.guest, &job.dest);
+
+        /* get the kernel image out of whatever it was published in, and make sure it's the one expected */
+        let temp = temp_pathname(dest);
+        let result = match archive::needs_unpacking(raw, &guest.format, &guest.member, label)
+        {
+            Ok(true) => archive::unpack(raw, &temp, &guest.format, &guest.member, label),
+            Ok(false) => digest::copy_file(raw, &temp),
+            Err(e) => Err(e)
+        };
+
+        let digests = match result.and_then(|d| verify_checksums(label, guest, &d).map(|_| d))
+        {
+            Ok(d) => d,
+            Err(e) =>
+            {
+                let _ = remove_file(&temp);
+                return Err(e);
+            }
+        };
+
+        if job.as_published == true
+        {
+            let _ = remove_file(&temp);
+            write_atomically(&signature_pathname(dest), signature.as_bytes())?;
+            cache::link(raw, dest)?;
+            return Ok(true);
+        }
+
+        /* leave a revalidated guest alone if it hasn't really changed */
+        if job.revalidate == true && digest::hash_file(dest).is_ok_and(|existing| existing == digests)
+        {
+            let _ = remove_file(&temp);
+            return Ok(false);
+        }
+
+        if let Err(e) = rename(&temp, dest)
+        {
+            let _ = remove_file(&temp);
+            return Err(format!("Can't move {} into place as {}: {}", temp.display(), dest.display(), e));
+        }
+
+        Ok(true)
+    }
+
+    /* make a conditional HEAD request for a guest using the validators from its last download, so that
+       a guest that has changed is only sent once, when it's downloaded afresh
+       => label = guest's label
+          dest = pathname of the guest kernel image
+       <= returns the URL the guest came from if it hasn't changed, None if it has or there's
+          no way of telling, or an error message */
+    async fn unchanged(&self, label: &String, dest: &Path) -> Result<Option<String>, String>
+    {
+        let validators = match Validators::load(dest)
+        {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+
+        self.policy.check_url(&validators.url)?;
+        let response = match timeout(self.read_timeout, validators.apply(self.request(Method::HEAD, &validators.url)?).send()).await
+        {
+            Ok(Ok(r)) => r,
+            Ok(Err(e)) => return Err(format!("Can't check {} for {}: {}", validators.url, label, e)),
+            Err(_) => return Err(format!("Timed out checking {} for {}", validators.url, label))
+        };
+
+        /* a server that won't answer HEAD requests can still be asked with a download */
+        match response.status()
+        {
+            StatusCode::NOT_MODIFIED => Ok(Some(validators.url)),
+            s if s.is_success() == true => Ok(None),
+            StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED => Ok(None),
+            s => Err(format!("Server returned {} for {} for {}", s, validators.url, label))
+        }
+    }
+
+    /* place a symlink to a guest's local file at dest, once the file has passed verification.
+       the file can't need unpacking, as the symlink has to point at the kernel image itself
+       => url = file:// URL or manifest-relative pathname of the guest kernel image
+          label = guest's label
+          guest = guest's configuration
+          dest = pathname to place the symlink
+       <= returns the guest's URL on success, or an error message */
+    async fn symlink_guest(&self, url: &String, label: &String, guest: &Guest, dest: &PathBuf) -> Result<String, String>
+    {
+        let source = match self.local_path(url)
+        {
+            Some(s) => s,
+            None => return Err(format!("Guest {} can only be symlinked to a local file, not {}", label, url))
+        };
+
+        self.verify_signature(&source, label, guest).await?;
+        if archive::needs_unpacking(&source, &guest.format, &guest.member, label)? == true
+        {
+            return Err(format!("Guest {} can't be symlinked to {} as it needs unpacking", label, source.display()));
+        }
+
+        if let Err(e) = verify_checksums(label, guest, &digest::hash_file(&source)?)
+        {
+            return Err(format!("Local {} failed verification: {}", url, e));
+        }
+
+        if self.verbose == true
+        {
+            self.report(format!("Linking guest OS {} to {}...", &guest.description, source.display()));
+        }
+
+        let source = match source.canonicalize()
+        {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Can't resolve {} for {}: {}", source.display(), label, e))
+        };
+
+        let temp = temp_pathname(dest);
+        let _ = remove_file(&temp);
+        if let Err(e) = symlink(&source, &temp)
+        {
+            return Err(format!("Can't link {} to {} for {}: {}", temp.display(), source.display(), label, e));
+        }
+
+        if let Err(e) = rename(&temp, dest)
+        {
+            let _ = remove_file(&temp);
+            return Err(format!("Can't move {} into place as {}: {}", temp.display(), dest.display(), e));
+        }
+
+        Ok(url.clone())
+    }
+
+    /* return true if a URL refers to a local file rather than something on the network */
+    pub fn is_local(&self, url: &String) -> bool
+    {
+        self.local_path(url).is_some()
+    }
+
+    /* work out the local file a URL refers to, if it's not a network URL
+       => url = file:// URL, pathname relative to the manifest configuration file, or network URL
+       <= returns pathname of the local file, or None for a network URL */
+    fn local_path(&self, url: &String) -> Option<PathBuf>
+    {
+        if url.starts_with("file://") == true
+        {
+            return match Url::parse(url).ok().and_then(|u| u.to_file_path().ok())
+            {
+                Some(path) => Some(path),
+                None => Some(PathBuf::from(&url["file://".len()..]))
+            };
+        }
+
+        match url.contains("://")
+        {
+            true => None,
+            false =>
+            {
+                let mut path = self.base.clone();
+                path.push(url);
+                Some(path)
+            }
+        }
+    }
+
+    /* download a guest as published from the given URL or its mirrors
+       => url = guest's main URL
+          label = guest's label
+          guest = guest's configuration
+          dest = pathname the guest kernel image will be written to. the partial download is kept alongside it
+       <= returns the pathname of the downloaded file, which is alongside dest, its size and hashes, the URL it came from,
+          and the server's validators if any on success, or an error message describing the failure */
+    pub async fn download(&self, url: &str, label: &String, guest: &Guest, dest: &Path) -> Result<(PathBuf, Digests, String, Option<Validators>), String>
+    {
+        let partial = download_pathname(dest);
+
+        /* try each source in turn until one works */
+        let sources = self.sources(url, guest);
+        let mut errors = Vec::new();
+        let mut source = None;
+        let mut received = None;
+        for (index, candidate) in sources.iter().enumerate()
+        {
+            /* a partial download is only resumed from the source it came from, so parts of
+               different mirrors' files are never stitched together */
+            let result = self.download_from(candidate, label, guest, &partial).await;
+            self.progress.finish(label);
+            match result
+            {
+                Ok(r) =>
+                {
+                    source = Some(candidate);
+                    received = Some(r);
+                    break;
+                },
+                Err(e) =>
+                {
+                    if self.verbose == true && index + 1 < sources.len()
+                    {
+                        self.report(format!("{}, trying next mirror...", e));
+                    }
+                    errors.push(e);
+                }
+            }
+        }
+
+        let (url, (raw_digests, validators)) = match (source, received)
+        {
+            (Some(s), Some(r)) => (s, r),
+            (_, _) => return Err(errors.join("; "))
+        };
+
+        /* the download is complete, so there's nothing left to resume */
+        Validators::remove(&partial);
+
+        if self.verbose == true
+        {
+            self.report(format!("Downloaded guest OS {} from {}", &guest.description, url));
+        }
+
+        Ok((partial, raw_digests, url.clone(), validators))
+    }
+
+    /* unpack an unsigned guest's downloaded file if need be, and check the guest kernel image's hashes
+       => raw = pathname of the file as downloaded
+          raw_digests = size and hashes of the file as downloaded
+          unpacked = pathname to unpack the guest kernel image into, if it needs unpacking
+          url = URL the file came from
+          label = guest's label
+          guest = guest's configuration
+       <= returns the pathname of the guest kernel image, which is either raw or unpacked,
+          and its size and hashes, or an error message */
+    fn verify_download(&self, raw: &PathBuf, raw_digests: Digests, unpacked: &PathBuf, url: &String, label: &String, guest: &Guest) -> Result<(PathBuf, Digests), String>
+    {
+        /* get the kernel image out of whatever it was published in */
+        let (image, digests) = match archive::needs_unpacking(raw, &guest.format, &guest.member, label)?
+        {
+            true =>
+            {
+                let digests = archive::unpack(raw, unpacked, &guest.format, &guest.member, label)?;
+                let _ = remove_file(raw);
+                (unpacked.clone(), digests)
+            },
+            false => (raw.clone(), raw_digests)
+        };
+
+        /* don't let a corrupted or tampered guest anywhere near storage */
+        if let Err(e) = verify_checksums(label, guest, &digests)
+        {
+            return Err(format!("Downloaded {} failed verification: {}", url, e));
+        }
+
+        Ok((image, digests))
+    }
+
+    /* make sure a signed guest is what its publisher signed, fetching its signature to check it with
+       => path = pathname of the file as published
+          label = guest's label
+          guest = guest's configuration
+       <= returns Ok if the guest isn't signed or its signature is good, or an error message */
+    pub async fn verify_signature(&self, path: &Path, label: &String, guest: &Guest) -> Result<(), String>
+    {
+        match &guest.signature
+        {
+            Some(location) =>
+            {
+                let signature = self.fetch_signature(location, label).await?;
+                self.check_signature(path, &signature, label, guest)
+            },
+            None => Ok(())
+        }
+    }
+
+    /* check a signed guest against its signature
+       => path = pathname of the file as published
+          signature = contents of the guest's minisign signature
+          label = guest's label
+          guest = guest's configuration
+       <= returns Ok if the signature is good, or an error message */
+    fn check_signature(&self, path: &Path, signature: &str, label: &String, guest: &Guest) -> Result<(), String>
+    {
+        let key = match &guest.key
+        {
+            Some(k) => k,
+            None => return Err(format!("Guest {} has a signature but no key to check it with", label))
+        };
+
+        signature::verify(path, signature, key, &self.keys, label)?;
+
+        if self.verbose == true
+        {
+            self.report(format!("Verified signature of guest OS {} using key {}", &guest.description, key));
+        }
+
+        Ok(())
+    }
+
+    /* start a request for a URL, with a bearer token if its host needs one
+       => method = GET, or HEAD to ask about the URL without fetching it
+          url = URL to fetch
+       <= returns the request, or an error message if the host's token can't be read or sent */
+    fn request(&self, method: Method, url: &String) -> Result<RequestBuilder, String>
+    {
+        let request = self.client.request(method, url);
+        match self.credentials.token(url)?
+        {
+            Some(token) => auth::bearer(request, url, token),
+            None => Ok(request)
+        }
+    }
+
+    /* get the contents of a guest's detached signature
+       => location = URL of the signature, or its pathname relative to the manifest configuration file
+          label = guest's label
+       <= returns the signature, or an error message */
+    async fn fetch_signature(&self, location: &String, label: &String) -> Result<String, String>
+    {
+        if let Some(path) = self.local_path(location)
+        {
+            return read_to_string(&path).map_err(|e| format!("Can't read signature {} for {}: {}", path.display(), label, e));
+        }
+
+        if self.offline == true
+        {
+            return Err(format!("Can't fetch signature {} for {} while offline", location, label));
+        }
+
+        self.policy.check_url(location)?;
+
+        let response = match timeout(self.read_timeout, self.request(Method::GET, location)?.send()).await
+        {
+            Ok(Ok(r)) => r,
+            Ok(Err(e)) => return Err(format!("Can't fetch signature {} for {}: {}", location, label, e)),
+            Err(_) => return Err(format!("Timed out fetching signature {} for {}", location, label))
+        };
+
+        if response.status().is_success() == false
+        {
+            return Err(format!("Server returned {} for signature {} for {}", response.status(), location, label));
+        }
+
+        match response.text().await
+        {
+            Ok(t) => Ok(t),
+            Err(e) => Err(format!("Failed to download signature {} for {}: {}", location, label, e))
+        }
+    }
+
+    /* download a guest's layer from an OCI registry into partial, and check it against the layer's digest
+       => url = oci:// URL of the guest
+          label = guest's label
+          guest = guest's configuration
+          partial = pathname of the file to download into
+       <= returns the layer's size and hashes on success, or an error message.
+          registries' validators aren't kept, as blobs never change */
+    async fn download_oci(&self, url: &String, label: &String, guest: &Guest, partial: &PathBuf) -> Result<(Digests, Option<Validators>), String>
+    {
+        let reference = oci::Reference::parse(url)?;
+        let base = reference.base_url();
+        self.policy.check_url(&base)?;
+
+        let token = self.credentials.token(&base)?.cloned();
+        let blob = oci::resolve(&self.client, &reference, &guest.layer, token, &self.policy, self.read_timeout, label).await?;
+        self.policy.check_size(blob.size, url)?;
+
+        let (digests, _) = self.retry(&blob.url, &blob.token, label, partial).await?;
+        if let Err(e) = oci::check_digest(&digests, &blob.digest, &blob.url)
+        {
+            let _ = remove_file(partial);
+            return Err(e);
+        }
+
+        Ok((digests, None))
+    }
+
+    /* print a message, keeping it clear of any download progress lines and free of secrets */
+    fn report(&self, msg: String)
+    {
+        self.progress.println(self.credentials.redact(msg));
+    }
+
+    /* fetch a guest from a URL, local file, or OCI registry into partial
+       => url = location to fetch
+          label = guest's label
+          guest = guest's configuration
+          partial = pathname of the file to download into
+       <= returns the file's size and hashes, and the server's validators if any, once the whole file is received,
+          or an error message */
+    async fn download_from(&self, url: &String, label: &String, guest: &Guest, partial: &PathBuf) -> Result<(Digests, Option<Validators>), String>
+    {
+        /* local files just need copying. a copy that fails part-way mustn't be resumed from elsewhere */
+        if let Some(path) = self.local_path(url)
+        {
+            Validators::remove(partial);
+            return match digest::copy_file(&path, partial)
+            {
+                Ok(digests) => Ok((digests, None)),
+                Err(e) => Err(format!("Can't fetch {}: {}", label, e))
+            };
+        }
+
+        if oci::is_oci(url) == true
+        {
+            return self.download_oci(url, label, guest, partial).await;
+        }
+
+        self.policy.check_url(url)?;
+        self.retry(url, &None, label, partial).await
+    }
+
+    /* download a URL into partial, retrying with exponential backoff if it fails for a temporary reason
+       => url = location to fetch
+          token = bearer token to send instead of the manifest's token for the URL's host, if any
+          label = guest's label
+          partial = pathname of the file to download into
+       <= returns the file's size and hashes, and the server's validators if any, once the whole body is received,
+          or an error message */
+    async fn retry(&self, url: &String, token: &Option<String>, label: &String, partial: &PathBuf) -> Result<(Digests, Option<Validators>), String>
+    {
+        let mut delay = self.backoff;
+        let mut attempt = 0;
+
+        loop
+        {
+            match self.attempt(url, token, label, partial).await
+            {
+                Ok(received) => return Ok(received),
+                Err(Failure::Permanent(e)) => return Err(e),
+                Err(Failure::Temporary(e)) =>
+                {
+                    if attempt >= self.retries
+                    {
+                        return Err(format!("{} (gave up after {} attempts)", e, attempt + 1));
+                    }
+
+                    if self.verbose == true
+                    {
+                        self.report(format!("{}, retrying in {} ms...", e, delay.as_millis()));
+                    }
+
+                    delay_for(delay).await;
+                    delay = min(delay * 2, Duration::from_millis(BACKOFF_MAX));
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /* make one attempt at downloading a URL into partial, resuming from the end of partial if it exists
+       => url = location to fetch
+          token = bearer token to send instead of the manifest's token for the URL's host, if any
+          label = guest's label
+          partial = pathname of the file to download into
+       <= returns the file's size and hashes, and the server's validators if any, once the whole body is received,
+          or how the attempt failed */
+    async fn attempt(&self, url: &String, token: &Option<String>, label: &String, partial: &PathBuf) -> Result<(Digests, Option<Validators>), Failure>
+    {
+        let mut offset = match metadata(partial)
+        {
+            Ok(m) => m.len(),
+            Err(_) => 0
+        };
+
+        /* only resume what came from this URL, and only if the server can be asked to start over if it's changed */
+        let resume = match offset > 0
+        {
+            true => Validators::load(partial).filter(|v| v.url == *url).and_then(|v| v.if_range().cloned()),
+            false => None
+        };
+        if offset > 0 && resume.is_none() == true
+        {
+            let _ = remove_file(partial);
+            offset = 0;
+        }
+
+        let request = match token
+        {
+            Some(t) => auth::bearer(self.client.get(url), url, t),
+            None => self.request(Method::GET, url)
+        };
+        let mut request = match request
+        {
+            Ok(r) => r,
+            Err(e) => return Err(Failure::Permanent(e))
+        };
+        if let Some(validator) = &resume
+        {
+            request = request.header(RANGE, format!("bytes={}-", offset)).header(IF_RANGE, validator.as_str());
+        }
+
+        let mut response = match timeout(self.read_timeout, request.send()).await
+        {
+            Ok(Ok(r)) => r,
+            Ok(Err(e)) if e.is_redirect() == true => return Err(Failure::Permanent(format!("Can't fetch {} for {}: {}", url, label, e))),
+            Ok(Err(e)) => return Err(Failure::Temporary(format!("Can't fetch {} for {}: {}", url, label, e))),
+            Err(_) => return Err(Failure::Temporary(format!("Timed out waiting for {} for {}", url, label)))
+        };
+
+        /* work out whether we're resuming or starting again */
+        let status = response.status();
+        let append = match status
+        {
+            StatusCode::PARTIAL_CONTENT if offset > 0 =>
+            {
+                /* make sure the server is carrying on from where we left off */
+                if range_start(response.headers()) != Some(offset)
+                {
+                    let _ = remove_file(partial);
+                    return Err(Failure::Temporary(format!("Server resumed {} for {} from the wrong place", url, label)));
+                }
+                true
+            },
+            StatusCode::PARTIAL_CONTENT => return Err(Failure::Permanent(format!("Server sent part of {} for {} unasked", url, label))),
+            s if s.is_success() == true => false,
+            StatusCode::RANGE_NOT_SATISFIABLE =>
+            {
+                /* our partial download is no use to the server, so start over */
+                let _ = remove_file(partial);
+                return Err(Failure::Temporary(format!("Server can't resume {} for {}", url, label)));
+            },
+            StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS =>
+                return Err(Failure::Temporary(format!("Server returned {} for {} for {}", status, url, label))),
+            s if s.is_server_error() == true =>
+                return Err(Failure::Temporary(format!("Server returned {} for {} for {}", status, url, label))),
+
+            /* don't mistake an error page for a guest kernel */
+            _ => return Err(Failure::Permanent(format!("Server returned {} for {} for {}", status, url, label)))
+        };
+
+        let expected_length = response.content_length();
+        let validators = Validators::from_headers(url, response.headers());
+        let offset = match append
+        {
+            true => offset,
+            false => 0
+        };
+        self.progress.begin(label, offset, expected_length.map(|length| offset + length));
+
+        /* refuse oversized downloads up front if possible */
+        if let Some(length) = expected_length
+        {
+            if let Err(e) = self.policy.check_size(offset + length, url)
+            {
+                return Err(Failure::Permanent(e));
+            }
+        }
+
+        let mut fh = match OpenOptions::new().create(true).write(true).append(append).truncate(!append).open(partial)
+        {
+            Ok(fh) => fh,
+            Err(e) => return Err(Failure::Permanent(format!("Can't create {} for {}: {}", partial.display(), label, e)))
+        };
+
+        /* note where a new download is coming from, so that it's only resumed from there */
+        if append == false
+        {
+            if let Err(e) = Validators::new(url, response.headers()).save(partial)
+            {
+                return Err(Failure::Permanent(e));
+            }
+        }
+
+        /* hash the body as it arrives, starting with whatever's being resumed */
+        let mut hasher = Hasher::new();
+        if append == true
+        {
+            if let Err(e) = File::open(partial).and_then(|mut existing| hasher.update_from(&mut existing))
+            {
+                return Err(Failure::Permanent(format!("Can't read {} for {}: {}", partial.display(), label, e)));
+            }
+        }
+
+        /* write the body out as it arrives so it can be resumed if the connection drops */
+        let mut received: u64 = 0;
+        loop
+        {
+            let chunk = match timeout(self.read_timeout, response.chunk()).await
+            {
+                Ok(Ok(Some(chunk))) => chunk,
+                Ok(Ok(None)) => break,
+                Ok(Err(e)) => return Err(Failure::Temporary(format!("Failed to download {} for {}: {}", url, label, e))),
+                Err(_) => return Err(Failure::Temporary(format!("Timed out downloading {} for {}", url, label)))
+            };
+
+            if let Err(e) = fh.write_all(&chunk)
+            {
+                return Err(Failure::Permanent(format!("Failed to write {} for {}: {}", partial.display(), label, e)));
+            }
+            hasher.update(&chunk);
+            received += chunk.len() as u64;
+            self.progress.advance(label, chunk.len() as u64);
+
+            /* and as they arrive, in case the server didn't say how big they are or lied */
+            if let Err(e) = self.policy.check_size(offset + received, url)
+            {
+                let _ = remove_file(partial);
+                return Err(Failure::Permanent(e));
+            }
+        }
+
+        /* catch truncated downloads */
+        if let Some(length) = expected_length
+        {
+            if length != received
+            {
+                return Err(Failure::Temporary(format!("Downloaded {} bytes of {} for {} but expected {} bytes", received, url, label, length)));
+            }
+        }
+
+        Ok((hasher.finish(), validators))
+    }
+}
+
+/* find where a partial response starts, from its Content-Range header of bytes <start>-<end>/<size>
+   => headers = response's headers
+   <= returns the offset of the first byte sent, or None if the header is missing or can't be parsed */
+fn range_start(headers: &HeaderMap) -> Option<u64>
+{
+    let range = headers.get(CONTENT_RANGE)?.to_str().ok()?.trim();
+    let range = range.strip_prefix("bytes")?.trim_start();
+    range[..range.find('-')?].trim().parse::<u64>().ok()
+}
+
+/* work out what a guest is cached under: its URL, plus whichever part of what's there it uses.
+   signed guests are cached as published, so they're kept apart from guests cached unpacked
+   => url = guest's main URL
+      guest = guest's configuration
+   <= returns the guest's download cache key */
+fn cache_key(url: &String, guest: &Guest) -> String
+{
+    if guest.signature.is_some() == true
+    {
+        return match &guest.layer
+        {
+            Some(layer) => format!("{}#{}#signed", url, layer),
+            None => format!("{}#signed", url)
+        };
+    }
+
+    match (&guest.layer, &guest.member)
+    {
+        (None, None) => url.clone(),
+        (Some(layer), None) => format!("{}#{}", url, layer),
+        (None, Some(member)) => format!("{}#{}", url, member),
+        (Some(layer), Some(member)) => format!("{}#{}/{}", url, layer, member)
+    }
+}
+
+/* create a symlink at link pointing to target, or a copy of target on hosts without symlinks */
+#[cfg(unix)]
+fn symlink(target: &PathBuf, link: &PathBuf) -> std::io::Result<()>
+{
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn symlink(target: &PathBuf, link: &PathBuf) -> std::io::Result<()>
+{
+    std::fs::copy(target, link).map(|_| ())
+}
+
+/* write data to a temporary file next to dest, and then rename it into place.
+   the temporary file is removed if anything goes wrong
+   => dest = pathname of the file to write
+      data = bytes to write
+   <= returns Ok on success, or an error message describing the failure */
+pub fn write_atomically(dest: &PathBuf, data: &[u8]) -> Result<(), String>
+{
+    let temp = temp_pathname(dest);
+
+    let result = match File::create(&temp)
+    {
+        Ok(mut fh) => match fh.write_all(data).and_then(|_| fh.sync_all())
+        {
+            Ok(()) => rename(&temp, dest).map_err(|e| format!("Can't move {} into place as {}: {}", temp.display(), dest.display(), e)),
+            Err(e) => Err(format!("Failed to write {}: {}", temp.display(), e))
+        },
+        Err(e) => Err(format!("Can't create {}: {}", temp.display(), e))
+    };
+
+    if result.is_err() == true
+    {
+        let _ = remove_file(&temp);
+    }
+
+    result
+}
+
+/* generate the pathname of a temporary file to use while writing dest.
+   it's kept in the same directory so the final rename can't cross file systems, and it's
+   unique to this process and call so concurrent jobs and processes writing dest don't collide */
+pub fn temp_pathname(dest: &Path) -> PathBuf
+{
+    let count = TEMP_COUNTER.fetch_add(1, Ordering::SeqCst);
+    sibling_pathname(dest, format!("{}.{}.part", process::id(), count).as_str())
+}
+
+/* generate the pathname of the file that holds a download in progress for dest.
+   it's kept between runs so that the download can be resumed */
+pub fn download_pathname(dest: &Path) -> PathBuf
+{
+    sibling_pathname(dest, "download")
+}
+
+/* generate the pathname of a guest's copy in a vendor directory
+   => vendor = vendor directory, if any
+      label = guest's label
+   <= returns pathname of the vendored guest, or None if there's no vendor directory */
+pub fn vendored_pathname(vendor: &Option<PathBuf>, label: &String) -> Option<PathBuf>
+{
+    match vendor
+    {
+        Some(dir) =>
+        {
+            let mut path = dir.clone();
+            path.push("guests");
+            path.push(label);
+            Some(path)
+        },
+        None => None
+    }
+}
+
+/* generate the pathname of the signature kept alongside a signed guest vendored as published */
+pub fn signature_pathname(path: &Path) -> PathBuf
+{
+    let leafname = match path.file_name()
+    {
+        Some(l) => l.to_string_lossy().to_string(),
+        None => String::from("guest")
+    };
+
+    path.with_file_name(format!("{}.minisig", leafname))
+}
+
+/* generate the pathname of a hidden file next to dest with the given extension */
+pub fn sibling_pathname(dest: &Path, extension: &str) -> PathBuf
+{
+    let leafname = match dest.file_name()
+    {
+        Some(l) => l.to_string_lossy().to_string(),
+        None => String::from("guest")
+    };
+
+    /* dest may already be hidden, such as a download in progress */
+    match leafname.starts_with('.')
+    {
+        true => dest.with_file_name(format!("{}.{}", leafname, extension)),
+        false => dest.with_file_name(format!(".{}.{}", leafname, extension))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::path::Path;
+    use std::fs::{create_dir_all, read, remove_dir_all, write};
+    use reqwest::header::{HeaderValue, ETAG};
+    use flate2::{Compression, write::GzEncoder};
+
+    use crate::cache::DownloadCache;
+    use crate::testutil::{scratch, response, StandIn, SIGNED_GUEST, TEST_KEY, SIGNATURE};
+
+    /* create a fetcher that retries quickly and gives up waiting after a second */
+    fn fetcher(dir: &Path, retries: usize) -> Fetcher
+    {
+        let config: Config = toml::from_str(format!("[defaults]\n[downloads]\nretries = {}\nbackoff = 1\nread_timeout = 1\n", retries).as_str()).unwrap();
+        Fetcher::new(&config, dir, DownloadCache::new(dir.join("cache")), 1, None, false, false).unwrap()
+    }
+
+    /* leave a partial download behind, as if an earlier run was interrupted */
+    fn interrupted(partial: &Path, data: &[u8], url: &str, etag: Option<&str>)
+    {
+        write(partial, data).unwrap();
+        let mut headers = HeaderMap::new();
+        if let Some(e) = etag
+        {
+            headers.insert(ETAG, HeaderValue::from_str(e).unwrap());
+        }
+        Validators::new(url, &headers).save(partial).unwrap();
+    }
+
+    /* a job to fetch a guest into <dir>/<label>, with any settings beyond its path and description */
+    fn job(url: &str, label: &str, dir: &Path, guest_toml: &str) -> FetchJob
+    {
+        FetchJob
+        {
+            url: url.to_string(),
+            label: label.to_string(),
+            guest: toml::from_str(format!("path = \"guest\"\ndescription = \"Test\"\n{}", guest_toml).as_str()).unwrap(),
+            dest: dir.join(label),
+            refresh: false,
+            revalidate: false,
+            as_published: false
+        }
+    }
+
+    static BODY: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    #[tokio::test]
+    async fn resumes_with_if_range()
+    {
+        let dir = scratch("fetch-resume");
+        let partial = dir.join(".guest.download");
+        let server = StandIn::start(vec![
+            response("206 Partial Content", &[("Content-Range", format!("bytes 10-{}/{}", BODY.len() - 1, BODY.len()))], &BODY[10..])
+        ]);
+        interrupted(&partial, &BODY[..10], &server.url, Some("\"v1\""));
+
+        let (digests, _) = fetcher(&dir, 0).retry(&server.url, &None, &String::from("g"), &partial).await.ok().unwrap();
+        assert!(digests == digest::hash_data(BODY));
+        assert_eq!(read(&partial).unwrap(), BODY);
+
+        let requests = server.requests();
+        assert!(requests[0].contains("range: bytes=10-\r\n"));
+        assert!(requests[0].contains("if-range: \"v1\"\r\n"));
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn starts_over_if_the_remote_file_changed()
+    {
+        let dir = scratch("fetch-changed");
+        let partial = dir.join(".guest.download");
+        let changed = b"a completely different guest";
+        let server = StandIn::start(vec![ response("200 OK", &[("ETag", String::from("\"v2\""))], changed) ]);
+        interrupted(&partial, &BODY[..10], &server.url, Some("\"v1\""));
+
+        let (digests, _) = fetcher(&dir, 0).retry(&server.url, &None, &String::from("g"), &partial).await.ok().unwrap();
+        assert!(digests == digest::hash_data(changed));
+        assert_eq!(read(&partial).unwrap(), changed);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn discards_partial_downloads_it_cant_safely_resume()
+    {
+        let dir = scratch("fetch-discard");
+        let partial = dir.join(".guest.download");
+        let server = StandIn::start(vec![ response("200 OK", &[], BODY), response("200 OK", &[], BODY) ]);
+
+        /* a weak ETag can't be used with If-Range */
+        interrupted(&partial, &BODY[..10], &server.url, Some("W/\"v1\""));
+        fetcher(&dir, 0).retry(&server.url, &None, &String::from("g"), &partial).await.ok().unwrap();
+
+        /* nor can a partial download from somewhere else be resumed */
+        interrupted(&partial, &BODY[..10], &String::from("http://elsewhere/guest"), Some("\"v1\""));
+        let (digests, _) = fetcher(&dir, 0).retry(&server.url, &None, &String::from("g"), &partial).await.ok().unwrap();
+        assert!(digests == digest::hash_data(BODY));
+
+        for request in server.requests()
+        {
+            assert!(request.contains("range:") == false);
+        }
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn starts_over_if_resumed_from_the_wrong_place()
+    {
+        let dir = scratch("fetch-wrongplace");
+        let partial = dir.join(".guest.download");
+        let server = StandIn::start(vec![
+            response("206 Partial Content", &[("Content-Range", format!("bytes 0-{}/{}", BODY.len() - 1, BODY.len()))], BODY),
+            response("200 OK", &[], BODY)
+        ]);
+        interrupted(&partial, &BODY[..10], &server.url, Some("\"v1\""));
+
+        let (digests, _) = fetcher(&dir, 1).retry(&server.url, &None, &String::from("g"), &partial).await.ok().unwrap();
+        assert!(digests == digest::hash_data(BODY));
+        assert_eq!(read(&partial).unwrap(), BODY);
+        assert!(server.requests()[1].contains("range:") == false);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn starts_over_if_range_not_satisfiable()
+    {
+        let dir = scratch("fetch-416");
+        let partial = dir.join(".guest.download");
+        let server = StandIn::start(vec![ response("416 Range Not Satisfiable", &[], b""), response("200 OK", &[], BODY) ]);
+        interrupted(&partial, &BODY[..10], &server.url, Some("\"v1\""));
+
+        let (digests, _) = fetcher(&dir, 1).retry(&server.url, &None, &String::from("g"), &partial).await.ok().unwrap();
+        assert!(digests == digest::hash_data(BODY));
+        assert!(server.requests()[1].contains("range:") == false);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_then_gives_up()
+    {
+        let dir = scratch("fetch-retry");
+        let partial = dir.join(".guest.download");
+
+        let server = StandIn::start(vec![ response("503 Service Unavailable", &[], b""), response("200 OK", &[], BODY) ]);
+        let (digests, _) = fetcher(&dir, 1).retry(&server.url, &None, &String::from("g"), &partial).await.ok().unwrap();
+        assert!(digests == digest::hash_data(BODY));
+
+        let server = StandIn::start(vec![ response("503 Service Unavailable", &[], b""), response("503 Service Unavailable", &[], b"") ]);
+        match fetcher(&dir, 1).retry(&server.url, &None, &String::from("g"), &partial).await
+        {
+            Err(e) => assert!(e.contains("gave up after 2 attempts")),
+            Ok(_) => panic!("download should have failed")
+        }
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn doesnt_retry_client_errors()
+    {
+        let dir = scratch("fetch-404");
+        let partial = dir.join(".guest.download");
+        let server = StandIn::start(vec![ response("404 Not Found", &[], b"not here") ]);
+        assert!(fetcher(&dir, 3).retry(&server.url, &None, &String::from("g"), &partial).await.is_err());
+        assert_eq!(server.requests().len(), 1);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn retries_truncated_downloads()
+    {
+        let dir = scratch("fetch-truncated");
+        let partial = dir.join(".guest.download");
+        let mut truncated = response("200 OK", &[("ETag", String::from("\"v1\""))], BODY).unwrap();
+        truncated.truncate(truncated.len() - 10);
+        let server = StandIn::start(vec![
+            Some(truncated),
+            response("206 Partial Content", &[("Content-Range", format!("bytes 26-{}/{}", BODY.len() - 1, BODY.len()))], &BODY[26..])
+        ]);
+
+        let (digests, _) = fetcher(&dir, 1).retry(&server.url, &None, &String::from("g"), &partial).await.ok().unwrap();
+        assert!(digests == digest::hash_data(BODY));
+        assert!(server.requests()[1].contains("range: bytes=26-\r\n"));
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn times_out_waiting_for_a_response()
+    {
+        let dir = scratch("fetch-timeout");
+        let partial = dir.join(".guest.download");
+        let server = StandIn::start(vec![ None ]);
+        match fetcher(&dir, 0).retry(&server.url, &None, &String::from("g"), &partial).await
+        {
+            Err(e) => assert!(e.contains("Timed out")),
+            Ok(_) => panic!("download should have timed out")
+        }
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn doesnt_resume_another_mirrors_download()
+    {
+        let dir = scratch("fetch-mirrors");
+        let dest = dir.join("guest");
+        let partial = download_pathname(&dest);
+        let primary = StandIn::start(vec![ response("200 OK", &[], BODY) ]);
+        let mirror = StandIn::start(vec![]);
+        interrupted(&partial, b"the mirror", &mirror.url, Some("\"v1\""));
+
+        let guest: Guest = toml::from_str(format!("path = \"guest\"\ndescription = \"Test\"\nmirrors = [ \"{}\" ]\n", mirror.url).as_str()).unwrap();
+        let (image, digests, source, _) = fetcher(&dir, 0).download(&primary.url, &String::from("g"), &guest, &dest).await.unwrap();
+        assert!(digests == digest::hash_data(BODY));
+        assert_eq!(read(&image).unwrap(), BODY);
+        assert_eq!(source, primary.url);
+        assert!(primary.requests()[0].contains("range:") == false);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn refreshes_from_the_url_rather_than_the_vendor_directory()
+    {
+        let dir = scratch("fetch-vendor");
+        let server = StandIn::start(vec![ response("200 OK", &[], BODY) ]);
+        let config: Config = toml::from_str("[defaults]\n[downloads]\nretries = 0\n").unwrap();
+        let vendor = dir.join("vendor");
+        let fetcher = Fetcher::new(&config, &dir, DownloadCache::new(dir.join("cache")), 1, Some(vendor.clone()), false, false).unwrap();
+        create_dir_all(vendor.join("guests")).unwrap();
+        write(vendor.join("guests").join("g"), b"vendored").unwrap();
+
+        let mut job = job(&server.url, "g", &dir, "");
+
+        fetcher.fetch_guest(&job).await.unwrap();
+        assert_eq!(read(&job.dest).unwrap(), b"vendored");
+        assert!(server.requests().is_empty());
+
+        job.refresh = true;
+        let fetched = fetcher.fetch_guest(&job).await.unwrap();
+        assert_eq!(read(&job.dest).unwrap(), BODY);
+        assert_eq!(fetched.source, server.url);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn downloads_oci_layers_and_checks_their_digests()
+    {
+        let dir = scratch("fetch-oci");
+        let partial = dir.join(".guest.download");
+        let guest: Guest = toml::from_str("path = \"guest\"\ndescription = \"Test\"\n").unwrap();
+        let manifest = |data: &[u8]| response("200 OK", &[], format!(
+            r#"{{"schemaVersion":2,"layers":[{{"digest":"sha256:{}","size":{}}}]}}"#, digest::hash_data(data).sha256, BODY.len()).as_bytes());
+
+        let registry = StandIn::start(vec![ manifest(BODY), response("200 OK", &[], BODY) ]);
+        let url = format!("oci://{}/diosix/linux:6.1", registry.addr);
+        let (digests, validators) = fetcher(&dir, 0).download_oci(&url, &String::from("g"), &guest, &partial).await.unwrap();
+        assert!(digests == digest::hash_data(BODY) && validators.is_none());
+        assert_eq!(read(&partial).unwrap(), BODY);
+        assert!(registry.requests()[1].starts_with(format!("get /v2/diosix/linux/blobs/sha256:{} ", digests.sha256).as_str()));
+
+        /* a blob that isn't what the manifest says is thrown away */
+        let registry = StandIn::start(vec![ manifest(b"something else"), response("200 OK", &[], BODY) ]);
+        let url = format!("oci://{}/diosix/linux:6.1", registry.addr);
+        let e = fetcher(&dir, 0).download_oci(&url, &String::from("g"), &guest, &partial).await.err().unwrap();
+        assert!(e.contains("but expected sha256:"));
+        assert!(partial.exists() == false);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn leaves_unchanged_guests_alone()
+    {
+        let dir = scratch("fetch-unchanged");
+        let server = StandIn::start(vec![ response("304 Not Modified", &[], b"") ]);
+        let job = FetchJob { revalidate: true, ..job(&server.url, "g", &dir, "") };
+        interrupted(&job.dest, b"old", &server.url, Some("\"v1\""));
+
+        let fetched = fetcher(&dir, 0).fetch_guest(&job).await.unwrap();
+        assert!(fetched.replaced == false && fetched.source == server.url);
+        assert_eq!(read(&job.dest).unwrap(), b"old");
+
+        let requests = server.requests();
+        assert!(requests.len() == 1 && requests[0].starts_with("head /guest ") && requests[0].contains("if-none-match: \"v1\""));
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn downloads_changed_guests_once()
+    {
+        let dir = scratch("fetch-changed-guest");
+        let server = StandIn::start(vec![
+            response("200 OK", &[("ETag", String::from("\"v2\""))], b""),
+            response("200 OK", &[("ETag", String::from("\"v2\""))], BODY)
+        ]);
+        let job = FetchJob { revalidate: true, ..job(&server.url, "g", &dir, "") };
+        interrupted(&job.dest, b"old", &server.url, Some("\"v1\""));
+
+        let fetched = fetcher(&dir, 0).fetch_guest(&job).await.unwrap();
+        assert!(fetched.replaced == true && fetched.source == server.url);
+        assert_eq!(read(&job.dest).unwrap(), BODY);
+
+        /* only the download itself carries the guest */
+        let requests = server.requests();
+        assert!(requests.len() == 2 && requests[0].starts_with("head /guest ") && requests[1].starts_with("get /guest "));
+        assert!(read_to_string(sibling_pathname(&job.dest, "validators")).unwrap().contains("v2"));
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    /* create a fetcher that trusts the test key, and a job for a guest signed with it */
+    fn signed(dir: &Path, url: &str, vendor: Option<PathBuf>) -> (Fetcher, FetchJob)
+    {
+        let config: Config = toml::from_str(format!("[defaults]\n[downloads]\nretries = 0\n[signatures.keys]\ntest = \"{}\"\n", TEST_KEY).as_str()).unwrap();
+        let fetcher = Fetcher::new(&config, dir, DownloadCache::new(dir.join("cache")), 1, vendor, false, false).unwrap();
+        (fetcher, job(url, "g", dir, format!("signature = \"{}.minisig\"\nkey = \"test\"\n", url).as_str()))
+    }
+
+    #[tokio::test]
+    async fn checks_signed_downloads_before_keeping_them()
+    {
+        let dir = scratch("fetch-signed");
+        let server = StandIn::start(vec![
+            response("200 OK", &[], SIGNATURE.as_bytes()), response("200 OK", &[], b"a tampered guest kernel image"),
+            response("200 OK", &[], SIGNATURE.as_bytes()), response("200 OK", &[], SIGNED_GUEST)
+        ]);
+        let (fetcher, job) = signed(&dir, &server.url, None);
+
+        let e = fetcher.fetch_guest(&job).await.err().unwrap();
+        assert!(e.contains("Bad signature"));
+        assert!(job.dest.exists() == false);
+
+        fetcher.fetch_guest(&job).await.unwrap();
+        assert_eq!(read(&job.dest).unwrap(), SIGNED_GUEST);
+        assert!(server.requests()[0].starts_with("get /guest.minisig "));
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn checks_cached_signed_guests_again()
+    {
+        let dir = scratch("fetch-signed-cache");
+        let server = StandIn::start(vec![
+            response("200 OK", &[], SIGNATURE.as_bytes()), response("200 OK", &[], SIGNED_GUEST),
+            response("200 OK", &[], SIGNATURE.as_bytes()), response("200 OK", &[], SIGNED_GUEST)
+        ]);
+        let (fetcher, job) = signed(&dir, &server.url, None);
+        fetcher.fetch_guest(&job).await.unwrap();
+
+        /* a cached copy that still checks out is used as it is */
+        remove_file(&job.dest).unwrap();
+        fetcher.fetch_guest(&job).await.unwrap();
+        assert_eq!(read(&job.dest).unwrap(), SIGNED_GUEST);
+        assert!(server.requests().len() == 2);
+
+        /* and one that doesn't is fetched afresh */
+        let cached = dir.join("cache").join("signatures").join(digest::hash_data(SIGNED_GUEST).sha256);
+        assert_eq!(read_to_string(&cached).unwrap(), SIGNATURE);
+        write(&cached, SIGNATURE.replace("RUQV", "RUQW")).unwrap();
+        remove_file(&job.dest).unwrap();
+        fetcher.fetch_guest(&job).await.unwrap();
+        assert_eq!(read(&job.dest).unwrap(), SIGNED_GUEST);
+        assert!(server.requests().len() == 4);
+        assert_eq!(read_to_string(&cached).unwrap(), SIGNATURE);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn checks_vendored_signed_guests_again()
+    {
+        let dir = scratch("fetch-signed-vendor");
+        let vendor = dir.join("vendor");
+        let vendored = vendor.join("guests").join("g");
+        create_dir_all(vendor.join("guests")).unwrap();
+        write(&vendored, SIGNED_GUEST).unwrap();
+        write(signature_pathname(&vendored), SIGNATURE).unwrap();
+
+        let server = StandIn::start(vec![]);
+        let (fetcher, job) = signed(&dir, &server.url, Some(vendor.clone()));
+        fetcher.fetch_guest(&job).await.unwrap();
+        assert_eq!(read(&job.dest).unwrap(), SIGNED_GUEST);
+
+        write(&vendored, b"a tampered guest kernel image").unwrap();
+        let e = fetcher.fetch_guest(&job).await.err().unwrap();
+        assert!(e.contains("Vendored") && e.contains("Bad signature"));
+        assert!(server.requests().is_empty());
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn copies_local_files_without_caching_them()
+    {
+        let dir = scratch("fetch-local");
+        let fetcher = fetcher(&dir, 0);
+        create_dir_all(dir.join("images")).unwrap();
+        let image = dir.join("images").join("Image");
+        write(&image, BODY).unwrap();
+
+        let file_url = Url::from_file_path(&image).unwrap().to_string();
+        for url in &[file_url.as_str(), "images/Image"]
+        {
+            assert!(fetcher.is_local(&url.to_string()) == true);
+            let job = job(url, "g", &dir, "");
+            let fetched = fetcher.fetch_guest(&job).await.unwrap();
+            assert!(fetched.replaced == true && fetched.source == *url);
+            assert_eq!(read(&job.dest).unwrap(), BODY);
+            assert!(job.dest.symlink_metadata().unwrap().file_type().is_symlink() == false);
+        }
+        assert!(fetcher.is_local(&String::from("https://example.com/Image")) == false);
+
+        /* local files are used as they are now */
+        write(&image, b"changed").unwrap();
+        fetcher.fetch_guest(&job("images/Image", "g", &dir, "")).await.unwrap();
+        assert_eq!(read(dir.join("g")).unwrap(), b"changed");
+        assert!(dir.join("cache").join("objects").exists() == false);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn symlinks_local_files_only_if_they_dont_need_unpacking()
+    {
+        let dir = scratch("fetch-symlink");
+        let fetcher = fetcher(&dir, 0);
+        write(dir.join("Image"), BODY).unwrap();
+
+        let linked = job("Image", "g", &dir, "symlink = true\n");
+        fetcher.fetch_guest(&linked).await.unwrap();
+        assert!(linked.dest.symlink_metadata().unwrap().file_type().is_symlink() == cfg!(unix));
+        assert_eq!(read(&linked.dest).unwrap(), BODY);
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(BODY).unwrap();
+        write(dir.join("Image.gz"), encoder.finish().unwrap()).unwrap();
+        let e = fetcher.fetch_guest(&job("Image.gz", "g", &dir, "symlink = true\n")).await.err().unwrap();
+        assert!(e.contains("can't be symlinked") && e.contains("needs unpacking"));
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rewrites_sources_by_longest_prefix()
+    {
+        let dir = scratch("fetch-sources");
+        let config: Config = toml::from_str(concat!("[defaults]\n[downloads.rewrite]\n",
+            "\"https://example.com/\" = \"https://mirror.local/\"\n",
+            "\"https://example.com/linux/\" = \"https://kernels.local/\"\n")).unwrap();
+        let fetcher = Fetcher::new(&config, &dir, DownloadCache::new(dir.join("cache")), 1, None, false, false).unwrap();
+        let guest: Guest = toml::from_str(concat!("path = \"guest\"\ndescription = \"Test\"\n",
+            "mirrors = [ \"https://example.com/other\", \"https://elsewhere.org/linux/x\", \"https://kernels.local/x\" ]\n")).unwrap();
+
+        assert_eq!(fetcher.sources(&String::from("https://example.com/linux/x"), &guest), vec![
+            "https://kernels.local/x", "https://example.com/linux/x",
+            "https://mirror.local/other", "https://example.com/other",
+            "https://elsewhere.org/linux/x"]);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn parses_content_range()
+    {
+        let mut headers = HeaderMap::new();
+        assert_eq!(range_start(&headers), None);
+        headers.insert(CONTENT_RANGE, HeaderValue::from_static("bytes 100-199/200"));
+        assert_eq!(range_start(&headers), Some(100));
+        headers.insert(CONTENT_RANGE, HeaderValue::from_static("bytes */200"));
+        assert_eq!(range_start(&headers), None);
+    }
+
+    #[tokio::test]
+    async fn reports_every_failed_fetch()
+    {
+        let dir = scratch("fetch-many");
+        let config: Config = toml::from_str("[defaults]\n[downloads]\nretries = 0\nread_timeout = 1\n").unwrap();
+        let fetcher = Fetcher::new(&config, &dir, DownloadCache::new(dir.join("cache")), 3, None, false, false).unwrap();
+
+        let missing = StandIn::start(vec![ response("404 Not Found", &[], b"") ]);
+        let broken = StandIn::start(vec![ response("500 Internal Server Error", &[], b"") ]);
+        let working = StandIn::start(vec![ response("200 OK", &[], BODY) ]);
+
+        let (fetched, errors) = fetcher.fetch_guests(vec![
+            job(&missing.url, "a", &dir, ""), job(&working.url, "b", &dir, ""), job(&broken.url, "c", &dir, "")]).await;
+
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched.get("b").unwrap().source, working.url);
+        assert_eq!(read(dir.join("b")).unwrap(), BODY);
+
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().any(|e| e.contains(&missing.addr)));
+        assert!(errors.iter().any(|e| e.contains(&broken.addr)));
+        assert!(dir.join("a").exists() == false && dir.join("c").exists() == false);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn fetches_no_more_than_jobs_guests_at_once()
+    {
+        let dir = scratch("fetch-jobs");
+        let config: Config = toml::from_str("[defaults]\n[downloads]\nretries = 0\nread_timeout = 1\n").unwrap();
+        let fetcher = Fetcher::new(&config, &dir, DownloadCache::new(dir.join("cache")), 1, None, false, false).unwrap();
+
+        /* the first guest's server never answers, so the second can't start until the first times out */
+        let stalled = StandIn::start(vec![ None ]);
+        let waiting = StandIn::start(vec![ response("200 OK", &[], BODY) ]);
+        let jobs = vec![ job(&stalled.url, "a", &dir, ""), job(&waiting.url, "b", &dir, "") ];
+        let task = tokio::spawn(async move { fetcher.fetch_guests(jobs).await });
+
+        tokio::time::delay_for(Duration::from_millis(500)).await;
+        assert_eq!(stalled.requests().len(), 1);
+        assert_eq!(waiting.requests().len(), 0);
+
+        let (fetched, errors) = task.await.unwrap();
+        assert!(fetched.contains_key("b") && errors.len() == 1);
+        assert_eq!(waiting.requests().len(), 1);
+
+        let _ = remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,304 @@
+/* Record how each guest OS image was resolved in mkdmfs.lock
+ *
+ * The lockfile sits next to the manifest configuration file and, for every guest
+ * fetched from a network URL, records where it finally came from, its size, its
+ * SHA-256 hash, and when it was fetched. Later builds check their guests against
+ * it, so everyone building from the same manifest and lockfile gets the same image.
+ * Guests taken from local files aren't recorded, as they can change at any time.
+ * A lockfile loaded with --locked is never changed or written out: anything that
+ * would change it is an error instead.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use std::path::{Path, PathBuf};
+use std::fs::read_to_string;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use serde_derive::{Deserialize, Serialize};
+
+use super::fetch::write_atomically;
+use super::digest::{self, Digests};
+
+/* lockfile's leafname */
+pub static LOCKFILE: &str = "mkdmfs.lock";
+
+/* warn people off editing the lockfile by hand */
+static LOCKFILE_HEADER: &str = "# This file is automatically generated by mkdmfs.\n# It is not intended for manual editing.\n";
+
+/* a guest's locked resolution */
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct LockedGuest
+{
+    pub url: String,
+    pub size: u64,
+    pub sha256: String,
+    pub fetched: String
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct LockfileContents
+{
+    guest: BTreeMap<String, LockedGuest>
+}
+
+pub struct Lockfile
+{
+    path: PathBuf,
+    contents: LockfileContents,
+    locked: bool,
+    changed: bool
+}
+
+impl Lockfile
+{
+    /* load the lockfile in the given directory, or start an empty one if there isn't one yet
+       => dir = directory containing the manifest configuration file
+          locked = true if the lockfile mustn't be changed
+       <= returns the lockfile, or an error message if it can't be read or parsed */
+    pub fn load(dir: &Path, locked: bool) -> Result<Lockfile, String>
+    {
+        let path = dir.join(LOCKFILE);
+
+        let contents = match path.exists()
+        {
+            true => match read_to_string(&path)
+            {
+                Ok(c) => match toml::from_str(c.as_str())
+                {
+                    Ok(contents) => contents,
+                    Err(e) => return Err(format!("Can't parse lockfile {}: {}", path.display(), e))
+                },
+                Err(e) => return Err(format!("Can't read lockfile {}: {}", path.display(), e))
+            },
+            false => LockfileContents::default()
+        };
+
+        /* the hashes end up in download cache pathnames, so they mustn't be anything but hashes */
+        for (label, entry) in &contents.guest
+        {
+            if digest::is_sha256(&entry.sha256) == false
+            {
+                return Err(format!("Lockfile {} has a malformed SHA-256 hash for guest {}: {:?}", path.display(), label, entry.sha256));
+            }
+        }
+
+        Ok(Lockfile { path, contents, locked, changed: false })
+    }
+
+    /* look up a guest's locked resolution, if any */
+    pub fn get(&self, label: &String) -> Option<&LockedGuest>
+    {
+        self.contents.guest.get(label)
+    }
+
+    /* return true if a guest's locked resolution no longer agrees with the manifest, because it came from
+       a URL the guest no longer uses, or has a hash other than the one the manifest now expects
+       => label = guest's label
+          sources = URLs the guest can now be fetched from
+          sha256 = SHA-256 hash the manifest expects of the guest, if any
+       <= returns true if the guest is in the lockfile and is stale */
+    pub fn is_stale(&self, label: &String, sources: &[String], sha256: &Option<String>) -> bool
+    {
+        match self.get(label)
+        {
+            Some(entry) => sources.contains(&entry.url) == false ||
+                           sha256.as_ref().is_some_and(|h| h.trim().to_lowercase() != entry.sha256),
+            None => false
+        }
+    }
+
+    /* record a guest's resolution, replacing any previous one
+       => label = guest's label
+          url = URL the guest was actually fetched from
+          digests = guest kernel image's size and hashes
+       <= returns Ok on success, or an error message if the lockfile is locked */
+    pub fn insert(&mut self, label: &String, url: &str, digests: &Digests) -> Result<(), String>
+    {
+        if self.locked == true
+        {
+            return Err(match self.get(label)
+            {
+                Some(_) => format!("Guest {} doesn't match {} and --locked was given", label, LOCKFILE),
+                None => format!("Guest {} is missing from {} and --locked was given", label, LOCKFILE)
+            });
+        }
+
+        let entry = LockedGuest
+        {
+            url: url.to_string(),
+            size: digests.size,
+            sha256: digests.sha256.clone(),
+            fetched: humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+        };
+
+        self.contents.guest.insert(label.clone(), entry);
+        self.changed = true;
+        Ok(())
+    }
+
+    /* forget a guest's resolution so that it's resolved afresh
+       <= returns true if the guest was in the lockfile, or an error message if it was and the lockfile is locked */
+    pub fn remove(&mut self, label: &String) -> Result<bool, String>
+    {
+        if self.locked == true && self.get(label).is_some() == true
+        {
+            return Err(format!("Guest {} would have to be removed from {}, and --locked was given", label, LOCKFILE));
+        }
+
+        match self.contents.guest.remove(label)
+        {
+            Some(_) =>
+            {
+                self.changed = true;
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+
+    /* return true if the lockfile needs to be written out */
+    pub fn is_changed(&self) -> bool
+    {
+        self.changed
+    }
+
+    /* return the lockfile's pathname */
+    pub fn path(&self) -> &PathBuf
+    {
+        &self.path
+    }
+
+    /* write the lockfile out if it has changed
+       <= returns Ok on success, or an error message */
+    pub fn save(&mut self) -> Result<(), String>
+    {
+        if self.changed == false
+        {
+            return Ok(());
+        }
+
+        if self.locked == true
+        {
+            return Err(format!("Lockfile {} would need to change, and --locked was given", self.path.display()));
+        }
+
+        let contents = match toml::to_string(&self.contents)
+        {
+            Ok(c) => c,
+            Err(e) => return Err(format!("Can't generate lockfile {}: {}", self.path.display(), e))
+        };
+
+        write_atomically(&self.path, format!("{}\n{}", LOCKFILE_HEADER, contents).as_bytes())?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::fs::{read, remove_dir_all, remove_file, write};
+
+    use crate::testutil::scratch;
+
+    fn url(name: &str) -> String
+    {
+        format!("https://example.com/{}", name)
+    }
+
+    #[test]
+    fn saves_and_loads_entries()
+    {
+        let dir = scratch("lockfile-save");
+        let digests = digest::hash_data(b"kernel");
+
+        let mut lockfile = Lockfile::load(&dir, false).unwrap();
+        assert!(lockfile.get(&String::from("g")).is_none());
+        assert!(lockfile.is_changed() == false);
+
+        lockfile.insert(&String::from("g"), &url("g"), &digests).unwrap();
+        assert!(lockfile.is_changed() == true);
+        lockfile.save().unwrap();
+        assert!(lockfile.is_changed() == false);
+        assert!(read_to_string(dir.join(LOCKFILE)).unwrap().starts_with(LOCKFILE_HEADER));
+
+        let entry = Lockfile::load(&dir, false).unwrap().get(&String::from("g")).cloned().unwrap();
+        assert!(entry == lockfile.get(&String::from("g")).cloned().unwrap());
+        assert!(entry.url == url("g") && entry.size == digests.size && entry.sha256 == digests.sha256);
+
+        /* forgetting a guest that isn't there changes nothing */
+        let mut lockfile = Lockfile::load(&dir, false).unwrap();
+        assert!(lockfile.remove(&String::from("other")).unwrap() == false);
+        assert!(lockfile.is_changed() == false);
+        assert!(lockfile.remove(&String::from("g")).unwrap() == true);
+        lockfile.save().unwrap();
+        assert!(Lockfile::load(&dir, false).unwrap().get(&String::from("g")).is_none());
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn detects_stale_entries()
+    {
+        let dir = scratch("lockfile-stale");
+        let digests = digest::hash_data(b"kernel");
+        let label = String::from("g");
+
+        let mut lockfile = Lockfile::load(&dir, false).unwrap();
+        lockfile.insert(&label, &url("mirror"), &digests).unwrap();
+
+        assert!(lockfile.is_stale(&label, &[url("g"), url("mirror")], &None) == false);
+        assert!(lockfile.is_stale(&label, &[url("mirror")], &Some(digests.sha256.to_uppercase())) == false);
+        assert!(lockfile.is_stale(&label, &[url("g")], &None) == true);
+        assert!(lockfile.is_stale(&label, &[url("mirror")], &Some(digest::hash_data(b"other").sha256)) == true);
+        assert!(lockfile.is_stale(&String::from("new"), &[url("new")], &None) == false);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn never_changes_when_locked()
+    {
+        let dir = scratch("lockfile-locked");
+        let digests = digest::hash_data(b"kernel");
+        let label = String::from("g");
+
+        let mut lockfile = Lockfile::load(&dir, false).unwrap();
+        lockfile.insert(&label, &url("g"), &digests).unwrap();
+        lockfile.save().unwrap();
+        let before = read(dir.join(LOCKFILE)).unwrap();
+
+        let mut lockfile = Lockfile::load(&dir, true).unwrap();
+        assert!(lockfile.insert(&String::from("new"), &url("new"), &digests).err().unwrap().contains("missing from"));
+        assert!(lockfile.insert(&label, &url("g"), &digest::hash_data(b"other")).is_err());
+        assert!(lockfile.remove(&label).is_err());
+        assert!(lockfile.remove(&String::from("new")).unwrap() == false);
+        assert!(lockfile.is_changed() == false);
+        lockfile.save().unwrap();
+        assert_eq!(read(dir.join(LOCKFILE)).unwrap(), before);
+
+        /* a locked lockfile that doesn't exist stays that way */
+        remove_file(dir.join(LOCKFILE)).unwrap();
+        let mut lockfile = Lockfile::load(&dir, true).unwrap();
+        assert!(lockfile.insert(&label, &url("g"), &digests).is_err());
+        lockfile.save().unwrap();
+        assert!(dir.join(LOCKFILE).exists() == false);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rejects_malformed_hashes()
+    {
+        let dir = scratch("lockfile-malformed");
+        write(dir.join(LOCKFILE), "[guest.g]\nurl = \"https://example.com/g\"\nsize = 6\nsha256 = \"../../victim\"\nfetched = \"2020-01-01T00:00:00Z\"\n").unwrap();
+        assert!(Lockfile::load(&dir, false).err().unwrap().contains("malformed SHA-256"));
+
+        let _ = remove_dir_all(&dir);
+    }
+}
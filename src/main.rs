@@ -2,18 +2,36 @@
  * 
  * Create a DMFS image file to embed in a diosix hypervisor
  * 
- * usage: cargo run -- [--verbose] -m <manifest toml file> -t <target architecture> -q <quality> -o <outfile>
+ * usage: cargo run -- [--verbose] -m <manifest toml file> -t <target architecture> -q <quality> -o <outfile> -j <jobs>
+ *        cargo run -- [-m <manifest toml file>] cache <list | verify | prune> [--all]
+ *        cargo run -- [options] update [<guest label>...]
+ *        cargo run -- [options] vendor [<directory>]
  * 
  * Options:
  * <manifest toml file>  = pathname of manifest configuration file. if unspecified, it'll search up the tree for manifest.toml
  * <target architecture> = architecture prefix the hypervisor will run on. eg: riscv64gc-unknown-none-elf
  * <quality>             = 'debug' to use the debug-enabled build of components, or 'release' for the release-grade builds
  * <outfile>             = pathname of the generated dmfs image file
+ * <jobs>                = maximum number of guest OS images to download at once
  * --verbose             = output progress of the build
  * --skip-downloads      = don't download any guest OSes
  * --skip-buildroot      = don't build any guest OSes from source
  * --skip-services       = don't include any system services at all
  * --skip-guests         = don't include guest OSes at all
+ * --locked              = fail if mkdmfs.lock is missing an entry or would need to change
+ * --offline             = don't use the network, nor let cargo use it to build services. guests must be vendored, cached, or already present
+ * --refresh             = check guests already present against their URLs, and replace any that have changed
+ * --build-services      = build each included system service with cargo before including it
+ * 
+ * Subcommands:
+ * cache list            = list the guest images held in the download cache
+ * cache verify          = check every image in the download cache is intact
+ * cache prune [--all]   = remove corrupt and unreferenced entries from the download cache, or every entry with --all
+ * update [<label>...]   = fetch the given guests, or all of the target's guests if none are given, afresh and update
+ *                         their entries in mkdmfs.lock, then generate the image as usual
+ * vendor [<directory>]  = copy every guest fetched from a URL by any target into <directory>/guests/<label>.
+ *                         signed guests are copied as published, with their signatures in <label>.minisig.
+ *                         <directory> defaults to downloads.vendor
  * 
  * mkdmfs takes its settings from the command line, and if any are omitted, it falls back
  * to its TOML-compliant manifest configuration file. If the location of this file isn't specified on the command line,
@@ -25,19 +43,64 @@
  * defaults.outfile = pathname of generated image if <outfile> is unspecified
  * defaults.ram = number of megabytes of RAM to assign to a capsule if unspecified
  * defaults.cpus = number of virtual CPU cores to assign to a capsule if unspecified
+ * cache.path = directory of the shared guest download cache, overridden by the MKDMFS_CACHE environment variable.
+ *              defaults to $XDG_CACHE_HOME/mkdmfs or else $HOME/.cache/mkdmfs
+ * downloads.jobs = maximum number of guest OS images to download at once if <jobs> is unspecified. defaults to 4
+ * downloads.connect_timeout = seconds to wait to connect to a server before trying again. defaults to 30
+ * downloads.read_timeout = seconds to wait for more of a download to arrive before trying again. defaults to 60
+ * downloads.retries = number of times to retry a failed download before giving up. defaults to 3
+ * downloads.backoff = milliseconds to wait before the first retry, doubled for each retry after that. defaults to 1000
+ * downloads.rewrite.<prefix> = replacement prefix for guest URLs starting with <prefix>. the rewritten URL is tried first,
+ *                              followed by the original. the longest matching <prefix> is used
+ * downloads.proxy = URL of the proxy to send downloads through. the usual proxy environment variables are also honored
+ * downloads.proxy_auth_env = environment variable holding the proxy's credentials as <user>:<password>
+ * downloads.ca_certs = array of pathnames of PEM files of extra certificate authorities to trust
+ * downloads.auth.<host>.token_env = environment variable holding the bearer token to send to <host>
+ * downloads.auth.<host>.token_file = pathname of a file holding the bearer token to send to <host>
+ * downloads.https_only = true to only allow guests and signatures to be fetched over HTTPS. defaults to false
+ * downloads.allowed_hosts = array of the only host names that guests and signatures can be fetched from.
+ *                           *.<domain> allows any host in <domain>. unrestricted if unspecified
+ * downloads.max_size = largest download allowed, in bytes. unlimited if unspecified
+ * downloads.vendor = directory of vendored guests, created by the vendor subcommand and used by builds before the
+ *                    download cache and the network, except by update and --refresh. defaults to vendor
  * banners.path = pathname of the directory containing the arch-specific boot banners. <base target architecture>.txt will be included, if present
  * banners.welcome = pathname of the generic boot banner text file to be included
- * services.include = array of services to include in the dmfs image from the services directory
- * service.<name>.path = location of the service's source code directory (required)
- * service.<name>.description = description of what this service does (required)
+ * services.include = array of services to include in the dmfs image from the services directory. each is a service's
+ *                    <name>, or a { name = <name>, optional = true } table for a service that's left out, rather than
+ *                    an error, if it isn't defined
+ * services.scan.path = directory to scan for services to include in the dmfs image, after those in services.include
+ * services.scan.include = array of glob patterns of the names of scanned services to include. defaults to all of them
+ * services.scan.exclude = array of glob patterns of the names of scanned services to leave out
+ * service.<name>.path = location of the service's source code directory (required, unless found by services.scan)
+ * service.<name>.description = description of what this service does (required, unless its Cargo.toml has one)
+ * service.<name>.binary = name of the service's binary. defaults to the service crate's binary, or else <name>
+ * service.<name>.target_dir = cargo target directory the service is built in. defaults to the one cargo metadata reports
  * service.<name>.properties = array of permissions and other properties granted to this service
- * service.<name>.ram = 
- * service.<name>.cpus = 
+ * service.<name>.ram = number of megabytes of RAM to allocate for this service
+ * service.<name>.cpus = number of virtual CPU cores to allocate for this service
  * guest.<label>.path = host file system directory containing guest kernel image <label> (required)
- * guest.<label>.url = URL from which to fetch the guest kernel image if it's not present
+ * guest.<label>.url = URL from which to fetch the guest kernel image if it's not present. can be a file:// URL,
+ *                      or a pathname relative to the manifest configuration file
+ * guest.<label>.symlink = true to symlink a guest to its local file, rather than copy it. defaults to false
+ * guest.<label>.layer = title or digest of the layer to use if url is an oci://<registry>/<repository>:<tag> URL.
+ *                        not needed if the artifact has one layer
+ * guest.<label>.mirrors = array of URLs to try in turn if the guest can't be fetched from url
+ * guest.<label>.format = format of the file fetched from url: raw, gz, xz, zst, tar, tar.gz, tar.xz, or tar.zst.
+ *                         detected from the file's contents if unspecified
+ * guest.<label>.member = pathname of the guest kernel image inside a tar archive fetched from url
+ * guest.<label>.sha256 = expected SHA-256 hash of the guest kernel image, in hex, after any decompression or extraction
+ * guest.<label>.sha512 = expected SHA-512 hash of the guest kernel image, in hex, after any decompression or extraction
+ * guest.<label>.signature = URL, or pathname, of the minisign detached signature of the file fetched from url
+ * guest.<label>.key = name of the trusted key in signatures.keys that signed the file fetched from url
+ * guest.<label>.buildroot = buildroot source tree directory from which to build the guest kernel image, unless --skip-buildroot
+ * guest.<label>.defconfig = buildroot defconfig name, or pathname of a defconfig file, to build the guest (required if buildroot is set)
+ * guest.<label>.image = leafname of the built kernel image in buildroot's output images directory. defaults to Image
  * guest.<label>.description = brief description of this guest (required)
  * guest.<label>.ram = number of megabytes of RAM to allocate for this guest
  * guest.<label>.cpus = number of virtual CPU cores to allocate for this guest
+ * signatures.keys.<name> = trusted minisign public key, in base64, called <name>
+ * signatures.require_signed = true to refuse to include guests without a signature and key, other than those built by
+ *                             buildroot. defaults to false
  * target.<target architecture>.guests = array of <label>s for guests to include in the image for the target arch
  * 
  * Recognized properties:
@@ -45,6 +108,8 @@
  * service_console = allow it to register as console service
  * console_write = allow it to write direct to the console
  * console_read = allow it to read direct from the console
+ * ram=<n> = n megabytes of RAM allocated to the capsule, added by mkdmfs from the config file
+ * cpus=<n> = n virtual CPU cores allocated to the capsule, added by mkdmfs from the config file
  * 
  * The pathnames are relative to <manifest toml file> or the found manifest.toml
  * Base target architecture = riscv, aarch64, powerpc, etc.
@@ -61,11 +126,10 @@ extern crate serde;
 extern crate serde_derive;
 
 use std::env;
-use std::io;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
-use std::process::exit;
-use std::fs::{read_to_string, create_dir_all, File};
+use std::process::{exit, Command};
+use std::fs::{read_to_string, create_dir_all, copy, rename, remove_file, File};
 use std::collections::HashMap;
 
 extern crate reqwest;
@@ -73,16 +137,42 @@ extern crate reqwest;
 extern crate regex;
 use regex::Regex;
 
+extern crate sha2;
+extern crate hex;
+
 use clap::{*, App};
 use serde_derive::Deserialize;
 
+mod fetch;
+mod cache;
+mod archive;
+mod lockfile;
+mod signature;
+mod auth;
+mod policy;
+mod oci;
+mod validators;
+mod progress;
+mod digest;
+mod services;
+
+#[cfg(test)]
+mod testutil;
+
 use dmfs::{Manifest, ManifestObject, ManifestObjectType, ManifestObjectData};
 
+use cache::DownloadCache;
+use fetch::Fetcher;
+use lockfile::{Lockfile, LOCKFILE};
+
 /* define the manifest configutation TOML file */
 #[derive(Deserialize)]
 struct Config
 {
     defaults: Defaults,
+    cache: Option<Cache>,
+    downloads: Option<Downloads>,
+    signatures: Option<Signatures>,
     banners: Option<Banners>,
     services: Option<Services>,
     service: Option<HashMap<String, Service>>, 
@@ -95,7 +185,48 @@ struct Defaults
 {
     arch: Option<String>,
     quality: Option<String>,
-    outfile: Option<String>
+    outfile: Option<String>,
+    ram: Option<usize>,
+    cpus: Option<usize>
+}
+
+#[derive(Deserialize)]
+struct Cache
+{
+    path: Option<String>
+}
+
+#[derive(Deserialize)]
+struct Downloads
+{
+    jobs: Option<usize>,
+    connect_timeout: Option<u64>,
+    read_timeout: Option<u64>,
+    retries: Option<usize>,
+    backoff: Option<u64>,
+    rewrite: Option<HashMap<String, String>>,
+    vendor: Option<String>,
+    proxy: Option<String>,
+    proxy_auth_env: Option<String>,
+    ca_certs: Option<Vec<String>>,
+    auth: Option<HashMap<String, Auth>>,
+    https_only: Option<bool>,
+    allowed_hosts: Option<Vec<String>>,
+    max_size: Option<u64>
+}
+
+#[derive(Deserialize, Clone)]
+struct Auth
+{
+    token_env: Option<String>,
+    token_file: Option<String>
+}
+
+#[derive(Deserialize)]
+struct Signatures
+{
+    keys: Option<HashMap<String, String>>,
+    require_signed: Option<bool>
 }
 
 #[derive(Deserialize)]
@@ -108,23 +239,59 @@ struct Banners
 #[derive(Deserialize)]
 struct Services
 {
-    include: Option<Vec<String>>
+    include: Option<Vec<IncludedService>>,
+    scan: Option<ServicesScan>
 }
 
+/* an entry in services.include: either a service's name, or a table naming it and saying whether it's optional */
 #[derive(Deserialize)]
-struct Service
+#[serde(untagged)]
+enum IncludedService
 {
-    path: String,
-    description: String,
-    properties: Option<Vec<String>>
+    Name(String),
+    Entry { name: String, optional: Option<bool> }
 }
 
 #[derive(Deserialize)]
+struct ServicesScan
+{
+    path: String,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>
+}
+
+#[derive(Deserialize, Default)]
+struct Service
+{
+    path: Option<String>,
+    description: Option<String>,
+    binary: Option<String>,
+    target_dir: Option<String>,
+    properties: Option<Vec<String>>,
+    ram: Option<usize>,
+    cpus: Option<usize>
+}
+
+#[derive(Deserialize, Clone)]
 struct Guest
 {
     path: String,
     url: Option<String>,
-    description: String
+    mirrors: Option<Vec<String>>,
+    layer: Option<String>,
+    symlink: Option<bool>,
+    format: Option<String>,
+    member: Option<String>,
+    sha256: Option<String>,
+    sha512: Option<String>,
+    signature: Option<String>,
+    key: Option<String>,
+    buildroot: Option<String>,
+    defconfig: Option<String>,
+    image: Option<String>,
+    description: String,
+    ram: Option<usize>,
+    cpus: Option<usize>
 }
 
 #[derive(Deserialize)]
@@ -136,9 +303,25 @@ struct Target
 /* default manifest file name */
 static MANIFEST_FILE: &str = "manifest.toml";
 
+/* default leafname of a guest kernel image built by buildroot */
+static BUILDROOT_IMAGE: &str = "Image";
+
+/* default directory of vendored guests */
+static VENDOR_DIR: &str = "vendor";
+
+/* environment variable that overrides the download cache's location */
+static CACHE_ENV_VAR: &str = "MKDMFS_CACHE";
+
+/* default number of guests to download at once */
+static DOWNLOAD_JOBS: usize = 4;
+
 /* max attempts to search the host file system for a config file */
 static SEARCH_MAX: usize = 100;
 
+/* upper limits on the resources that can be assigned to a capsule */
+static RAM_MAX: usize = 64 * 1024; /* in megabytes */
+static CPUS_MAX: usize = 64;
+
 /* these could be fancy enums and whatnot but we're dealing primarily in strings in this program,
 so it seems an unnecessary faff at the moment to decode and re-encode them. we'll leave them as strings */
 struct Settings
@@ -146,15 +329,37 @@ struct Settings
     /* pathname of the manifest configuration file's parent directory */
     config_dir: PathBuf,
 
+    /* pathname of the shared download cache directory */
+    cache_dir: PathBuf,
+
+    /* pathname of the vendored guests directory */
+    vendor_dir: PathBuf,
+
+    /* set if we've been asked to vendor guests into this directory rather than generate an image */
+    vendor_command: Option<PathBuf>,
+
+    /* set if we've been asked to manage the download cache rather than generate an image */
+    cache_command: Option<String>,
+    cache_prune_all: bool,
+
     /* set by the command line, or from the configuration's file defaults, or None if unspecified */
     output_filename: Option<String>,
     target_arch: Option<String>,
     quality: Option<String>,
+    download_jobs: usize,
     verbose: bool,
     no_downloads: bool,
+    no_buildroot: bool,
     no_services: bool,
     no_guests: bool,
-    
+    locked: bool,
+    offline: bool,
+    refresh: bool,
+    build_services: bool,
+
+    /* set if we've been asked to refresh guests' lockfile entries. an empty list means all of the target's guests */
+    update: Option<Vec<String>>,
+
     /* set by the manifest configuration file */
     config: Config
 }
@@ -174,11 +379,32 @@ impl Settings
             -t, --target=[ARCH]   'Sets architecture of target system'
             -q, --quality=[LEVEL] 'Set whether this is a debug or release build'
             -o, --output=[FILE]   'Set location of generated image file'
+            -j, --jobs=[N]        'Set number of guest OS images to download at once'
             -v, --verbose         'Output progress of image creation'
             --skip-downloads      'Don't download guest OS images'
             --skip-buildroot      'Don't build guest OSes using buildroot'
             --skip-services       'Don't include system services'
-            --skip-guests         'Don't include guest OSes'")
+            --skip-guests         'Don't include guest OSes'
+            --locked              'Fail if the lockfile is missing an entry or would need to change'
+            --offline             'Don't use the network'
+            --refresh             'Replace guests whose remote copies have changed'
+            --build-services      'Build system services with cargo before including them'")
+        .subcommand(SubCommand::with_name("cache")
+            .about("Manage the shared guest download cache")
+            .arg(Arg::with_name("action")
+                .possible_values(&["list", "verify", "prune"])
+                .required(true)
+                .help("List, verify, or prune the cache's entries"))
+            .arg(Arg::from_usage("--all 'Remove every entry when pruning'")))
+        .subcommand(SubCommand::with_name("update")
+            .about("Fetch guests afresh and update their lockfile entries, then generate the image")
+            .arg(Arg::with_name("guests")
+                .multiple(true)
+                .help("Labels of the guests to update. Updates all of the target's guests if none are given")))
+        .subcommand(SubCommand::with_name("vendor")
+            .about("Copy every guest fetched from a URL into a vendor directory for offline builds")
+            .arg(Arg::with_name("directory")
+                .help("Directory to vendor guests into. Defaults to the manifest's downloads.vendor")))
         .get_matches();
 
         /* try to find the toml configuration file: first from the command line, and next by searching up through the tree */
@@ -211,50 +437,140 @@ impl Settings
             Err(e) => fatal_error(format!("Can't parse manifest configutation file {:?}: {}", config_location, e))
         };
 
+        /* guests' hashes are used to find them in the download cache, so make sure they really are hashes */
+        if let Some(guests) = &config.guest
+        {
+            for (label, guest) in guests
+            {
+                if let Some(hash) = &guest.sha256
+                {
+                    if digest::is_sha256(hash.trim()) == false
+                    {
+                        fatal_error(format!("Guest {} has a malformed sha256 in {:?}: it must be 64 hex digits", label, config_location));
+                    }
+                }
+            }
+        }
+
         /* get the settings from the command line, or fall back to defaults in the manifest config file, if any */
         let output_filename = match opts.value_of("output")
         {
             Some(of) => Some(String::from(of)),
-            None => match config.defaults.outfile
-            {
-                Some(ref s) => Some(s.clone()),
-                None => None
-            }
+            None => config.defaults.outfile.clone()
         };
         let target_arch = match opts.value_of("target")
         {
             Some(ta) => Some(String::from(ta)),
-            None => match config.defaults.arch 
-            {
-                Some(ref s) => Some(s.clone()),
-                None => None
-            }
+            None => config.defaults.arch.clone()
         };
         let quality = match opts.value_of("quality")
         {
             Some(q) => Some(String::from(q)),
-            None => match config.defaults.quality
+            None => config.defaults.quality.clone()
+        };
+
+        let download_jobs = match opts.value_of("jobs")
+        {
+            Some(j) => match j.parse::<usize>()
+            {
+                Ok(n) => n,
+                Err(_) => fatal_error(format!("Number of download jobs must be a number, not {}", j))
+            },
+            None => match config.downloads.as_ref().and_then(|d| d.jobs)
             {
-                Some(ref s) => Some(s.clone()),
-                None => None
+                Some(n) => n,
+                None => DOWNLOAD_JOBS
             }
         };
+        if download_jobs == 0
+        {
+            fatal_error(String::from("Number of download jobs must be at least 1"));
+        }
 
         /* these aren't defined in the toml, only at the command line */
         let verbose = opts.is_present("verbose");
         let no_downloads = opts.is_present("skip-downloads");
+        let no_buildroot = opts.is_present("skip-buildroot");
         let no_services  = opts.is_present("skip-services");
         let no_guests    = opts.is_present("skip-guests");
+        let locked       = opts.is_present("locked");
+        let offline      = opts.is_present("offline");
+        let refresh      = opts.is_present("refresh");
+        let build_services = opts.is_present("build-services");
 
-        /* generate a structure to hold all the settings together */
-        Settings
+        let update = opts.subcommand_matches("update").map(|u| match u.values_of("guests")
+        {
+            Some(labels) => labels.map(String::from).collect(),
+            None => Vec::new()
+        });
+        if update.is_some() && locked == true
+        {
+            fatal_error(String::from("Can't update the lockfile when --locked is given"));
+        }
+        if update.is_some() && offline == true
+        {
+            fatal_error(String::from("Can't fetch guests afresh to update the lockfile when --offline is given"));
+        }
+        if refresh == true && (locked == true || offline == true || no_downloads == true)
+        {
+            fatal_error(String::from("Can't refresh guests when --locked, --offline, or --skip-downloads is given"));
+        }
+
+        /* the cache subcommand and its options */
+        let (cache_command, cache_prune_all) = match opts.subcommand_matches("cache")
+        {
+            Some(c) => (c.value_of("action").map(String::from), c.is_present("all")),
+            None => (None, false)
+        };
+
+        /* save the directory pathname of where we read in our config */
+        let config_dir = match config_location.parent()
+        {
+            Some(p) => p.to_path_buf(),
+            None => fatal_error(String::from("Can't get directory of manifest configuration file"))
+        };
+
+        /* find the download cache: the environment overrides the config file, which overrides the user's cache directory */
+        let cache_dir = match (env::var_os(CACHE_ENV_VAR), config.cache.as_ref().and_then(|c| c.path.as_ref()))
         {
-            /* save the directory pathname of where we read in our config */
-            config_dir: match config_location.parent()
+            (Some(dir), _) => PathBuf::from(dir),
+            (None, Some(dir)) =>
             {
-                Some(p) => p.to_path_buf(),
-                None => fatal_error(format!("Can't get directory of manifest configuration file"))
+                let mut pb = config_dir.clone();
+                pb.push(dir);
+                pb
             },
+            (None, None) => match (env::var_os("XDG_CACHE_HOME"), env::var_os("HOME"))
+            {
+                (Some(dir), _) => [PathBuf::from(dir), PathBuf::from("mkdmfs")].iter().collect(),
+                (None, Some(dir)) => [PathBuf::from(dir), PathBuf::from(".cache"), PathBuf::from("mkdmfs")].iter().collect(),
+                (None, None) => fatal_error(format!("Can't find a directory for the download cache. Set {} to choose one", CACHE_ENV_VAR))
+            }
+        };
+
+        /* find the vendor directory, and where to vendor guests to if asked */
+        let mut vendor_dir = config_dir.clone();
+        vendor_dir.push(match config.downloads.as_ref().and_then(|d| d.vendor.as_ref())
+        {
+            Some(dir) => dir.as_str(),
+            None => VENDOR_DIR
+        });
+
+        let vendor_command = opts.subcommand_matches("vendor").map(|v| match v.value_of("directory")
+        {
+            Some(dir) => PathBuf::from(dir),
+            None => vendor_dir.clone()
+        });
+
+        /* generate a structure to hold all the settings together */
+        Settings
+        {
+            config_dir,
+            cache_dir,
+            vendor_dir,
+            vendor_command,
+            cache_command,
+            cache_prune_all,
 
             /* stash our parsed toml config file */
             config,
@@ -262,11 +578,18 @@ impl Settings
             /* stash settings, either from the command line or the config file, or None for not specified */
             verbose,
             no_downloads,
+            no_buildroot,
             no_services,
             no_guests,
+            locked,
+            offline,
+            refresh,
+            build_services,
+            update,
             output_filename,
             target_arch,
-            quality
+            quality,
+            download_jobs
         }
     }
 }
@@ -279,6 +602,41 @@ async fn main() -> Result<()>
     will bail out if there's a problem with the cmd line arguments */
     let settings = Settings::new();
 
+    /* manage the download cache if asked to, rather than generate an image */
+    let cache = DownloadCache::new(settings.cache_dir.clone());
+    if let Some(command) = &settings.cache_command
+    {
+        manage_cache(&cache, command, settings.cache_prune_all);
+        return Ok(());
+    }
+
+    /* vendor guests if asked to, rather than generate an image. the vendor directory being filled
+    mustn't be used as a source of guests */
+    if let Some(dir) = &settings.vendor_command
+    {
+        let fetcher = match Fetcher::new(&settings.config, &settings.config_dir, cache, settings.download_jobs, None, settings.offline, settings.verbose)
+        {
+            Ok(f) => f,
+            Err(e) => fatal_error(e)
+        };
+
+        vendor_guests(&settings, &fetcher, dir).await;
+        return Ok(());
+    }
+
+    /* use vendored guests if there are any */
+    let vendor = match settings.vendor_dir.exists()
+    {
+        true => Some(settings.vendor_dir.clone()),
+        false => None
+    };
+
+    let fetcher = match Fetcher::new(&settings.config, &settings.config_dir, cache, settings.download_jobs, vendor, settings.offline, settings.verbose)
+    {
+        Ok(f) => f,
+        Err(e) => fatal_error(e)
+    };
+
     /* create an empty manifest object that describes the dmfs we want to generate */
     let mut manifest = Manifest::new();
 
@@ -294,7 +652,7 @@ async fn main() -> Result<()>
         {
             if let Some(target_arch) = &settings.target_arch
             {
-                if let Some(base_arch) = get_base_arch(&target_arch)
+                if let Some(base_arch) = get_base_arch(target_arch)
                 {
                     let mut p = base.clone();
                     p.push(&banner_dir);
@@ -320,7 +678,7 @@ async fn main() -> Result<()>
             (
                 ManifestObjectType::BootMsg,
                 Path::new(&welcome).file_name().unwrap().to_str().unwrap().to_string(),
-                format!("Main boot banner text"),
+                String::from("Main boot banner text"),
                 ManifestObjectData::Bytes(load_file(&p, settings.verbose)),
                 None
             ));
@@ -330,56 +688,142 @@ async fn main() -> Result<()>
     /* include the system services, if any are defined and if allowed */
     if let (Some(services), false) = (settings.config.services, settings.no_services)
     {
-        /* get the hashtable of defined available services */
-        if let Some(available_services) = settings.config.service
+        /* get the hashtable of defined available services, and the list of services to include */
+        let mut available_services = settings.config.service.unwrap_or_default();
+        let mut services_to_include: Vec<(String, bool)> = match services.include
         {
-            /* get the list of services to include */
-            if let Some(services_to_include) = services.include
+            Some(list) => list.into_iter().map(|entry| match entry
             {
-                /* run through that list */
-                for service_name in services_to_include
+                IncludedService::Name(name) => (name, false),
+                IncludedService::Entry { name, optional } => (name, optional == Some(true))
+            }).collect(),
+            None => Vec::new()
+        };
+
+        /* add the services found by scanning a directory, if asked to. their service tables, if any, take precedence */
+        if let Some(scan) = services.scan
+        {
+            let mut dir = base.clone();
+            dir.push(&scan.path);
+            let found = match services::scan(&dir, &scan.include, &scan.exclude)
+            {
+                Ok(names) => names,
+                Err(e) => fatal_error(e)
+            };
+
+            for service_name in found
+            {
+                let path = Path::new(&scan.path).join(&service_name).to_string_lossy().to_string();
+                let service = available_services.entry(service_name.clone()).or_default();
+                if service.path.is_none() == true
                 {
-                    /* look up the service from its name */
-                    if let Some(service) = available_services.get(&service_name)
-                    {
-                        /* drill down to the service's binary we want to include */
-                        let mut p = base.clone();
-                        p.push(&service.path);
-                        p.push("target");
-                        
-                        /* skip the arch directory if it doesn't exist -- may mean we're self-hosting */
-                        match &settings.target_arch
-                        {
-                            Some(ta) =>
-                            {
-                                let mut test = p.clone();
-                                test.push(ta);
-                                if test.as_path().exists() == true
-                                {
-                                    p.push(&ta);
-                                }
-                            },
-                            None => ()
-                        }
+                    service.path = Some(path);
+                }
 
-                        /* select the appropriate debug or release build */
-                        if let Some(q) = &settings.quality
-                        {
-                            p.push(q);
-                            p.push(&service_name);
-                        }
+                if services_to_include.iter().any(|(name, _)| *name == service_name) == false
+                {
+                    services_to_include.push((service_name, false));
+                }
+            }
+        }
 
-                        manifest.add(ManifestObject::new
-                        (
-                            ManifestObjectType::SystemService,
-                            (&service_name).to_string(),
-                            service.description.clone(),
-                            ManifestObjectData::Bytes(load_file(&p, settings.verbose)),
-                            service.properties.clone()
-                        ));
+        /* run through that list */
+        for (service_name, optional) in services_to_include
+        {
+            /* look up the service from its name. a service that isn't defined is likely a typo, so say so unless it's optional */
+            let service = match (available_services.get(&service_name), optional)
+            {
+                (Some(s), _) => s,
+                (None, true) =>
+                {
+                    if settings.verbose == true
+                    {
+                        println!("Skipping optional service {}, which isn't defined", service_name);
                     }
-                }
+                    continue;
+                },
+                (None, false) => fatal_error(match services::suggest(&service_name, available_services.keys())
+                {
+                    Some(s) => format!("Service {} is included but not defined. Did you mean {}?", service_name, s),
+                    None => format!("Service {} is included but not defined. Add a service.{} table, or mark it optional", service_name, service_name)
+                })
+            };
+
+            let mut source = base.clone();
+            source.push(match &service.path
+            {
+                Some(p) => p,
+                None => fatal_error(format!("Service {} needs a path", service_name))
+            });
+
+            /* fill in anything the manifest leaves out from the service's Cargo.toml, noting where they disagree */
+            let from_crate = match services::crate_settings(&source)
+            {
+                Ok(s) => s,
+                Err(e) => fatal_error(e)
+            };
+
+            let mut conflicts = Vec::new();
+            let description = match services::merge(&service_name, "description", service.description.clone(), from_crate.description, &mut conflicts)
+            {
+                Some(d) => d,
+                None => fatal_error(format!("Service {} needs a description, in the manifest or as its Cargo.toml's package.description", service_name))
+            };
+            let properties = services::merge(&service_name, "properties", service.properties.clone(), from_crate.properties, &mut conflicts);
+            let ram = services::merge(&service_name, "ram", service.ram, from_crate.ram, &mut conflicts);
+            let cpus = services::merge(&service_name, "cpus", service.cpus, from_crate.cpus, &mut conflicts);
+
+            for conflict in &conflicts
+            {
+                eprintln!("mkdmfs warning: {}", conflict);
             }
+
+            /* cargo runs in the service's directory, so give it the same absolute target directory we look in */
+            let target_dir = service.target_dir.as_ref().map(|td| match env::current_dir()
+            {
+                Ok(cwd) => cwd.join(&base).join(td),
+                Err(e) => fatal_error(format!("Can't get current working directory to find service {}: {}", service_name, e))
+            });
+
+            let options = services::CargoOptions
+            {
+                target_dir,
+                target_arch: settings.target_arch.clone(),
+                quality: settings.quality.clone(),
+                offline: settings.offline,
+                verbose: settings.verbose
+            };
+
+            /* build the service with cargo if asked to, and pick up the binary it produces, or else find the one already built */
+            let p = match settings.build_services
+            {
+                true => match services::build(&source, &service_name, &service.binary, &options)
+                {
+                    Ok(binary) => binary,
+                    Err(e) => fatal_error(e)
+                },
+                false => match services::locate(&source, &service_name, &service.binary, &options)
+                {
+                    Ok(binary) => binary,
+                    Err(e) => fatal_error(e)
+                }
+            };
+
+            /* work out how much RAM and how many CPUs to give the service */
+            let properties = match add_resources(properties, &service_name, ram, cpus, &settings.config.defaults)
+            {
+                Ok(p) => p,
+                Err(e) => fatal_error(e)
+            };
+
+            manifest.add(ManifestObject::new
+            (
+                ManifestObjectType::SystemService,
+                service_name.to_string(),
+                description,
+                ManifestObjectData::Bytes(load_file(&p, settings.verbose)),
+                properties
+            ));
         }
     }
 
@@ -396,85 +840,280 @@ async fn main() -> Result<()>
                 if let Some(targets_guests) = &target_entry.guests
                 {
                     /* fetch the list of available guests */
-                    let available_guests = match settings.config.guest
+                    let available_guests = settings.config.guest.unwrap_or_default();
+
+                    let require_signed = match &settings.config.signatures
                     {
-                        Some(hashtbl) => hashtbl,
-                        None => HashMap::new()
+                        Some(s) => s.require_signed == Some(true),
+                        None => false
                     };
 
-                    /* and include the ones required by this target */
+                    /* gather up the ones required by this target, building them from source if possible */
+                    let mut selected = Vec::new();
                     for guest in targets_guests
                     {
-                        match available_guests.get(&guest.clone())
+                        let g = match available_guests.get(&guest.clone())
+                        {
+                            Some(g) => g,
+                            None => fatal_error(format!("Guest {} required by target architecture {} not defined", guest, target_arch))
+                        };
+
+                        /* generate path name of guest image */
+                        let mut path = base.clone();
+                        path.push(&g.path);
+                        /* make sure a directory is present to house the guest */
+                        if let Err(e) = create_dir_all(&path)
+                        {
+                            fatal_error(format!("Can't ensure directory {} exists for guest {} ({})",
+                                &path.to_str().unwrap(), &guest, e));
+                        }
+                        path.push(guest);
+
+                        let built = match (&g.buildroot, settings.no_buildroot)
+                        {
+                            (Some(tree), false) =>
+                            {
+                                build_guest(&base, guest, g, tree, &path, settings.offline, settings.verbose);
+                                true
+                            },
+                            (_, _) => false
+                        };
+
+                        /* enforce the signing policy */
+                        if built == false && require_signed == true && (g.signature.is_none() || g.key.is_none())
+                        {
+                            fatal_error(format!("Guest {} has no signature and key, and signatures.require_signed is set", guest));
+                        }
+
+                        selected.push((guest, g, path, built));
+                    }
+
+                    /* bring the lockfile up to date with the manifest, and forget the guests being updated */
+                    let mut lockfile = match Lockfile::load(&base, settings.locked)
+                    {
+                        Ok(l) => l,
+                        Err(e) => fatal_error(e)
+                    };
+
+                    if let Some(labels) = &settings.update
+                    {
+                        for label in labels
+                        {
+                            if selected.iter().any(|(guest, _, _, _)| *guest == label) == false
+                            {
+                                fatal_error(format!("Can't update guest {} as target architecture {} doesn't use it", label, target_arch));
+                            }
+                        }
+                    }
+
+                    let mut refreshing = Vec::new();
+                    for (guest, g, _, built) in &selected
+                    {
+                        /* only guests fetched from network URLs are locked. local files can change at any time */
+                        let url = match (&g.url, built)
+                        {
+                            (Some(url), false) => url,
+                            (_, _) => continue
+                        };
+
+                        if fetcher.is_local(url) == true
+                        {
+                            if let Err(e) = lockfile.remove(guest)
+                            {
+                                fatal_error(e);
+                            }
+                            continue;
+                        }
+
+                        let updating = match &settings.update
+                        {
+                            Some(labels) => labels.is_empty() || labels.contains(guest),
+                            None => false
+                        };
+
+                        let stale = lockfile.is_stale(guest, &fetcher.sources(url, g), &g.sha256);
+                        if stale == true && settings.locked == true
+                        {
+                            fatal_error(format!("Guest {} in {} no longer matches the manifest and --locked was given", guest, LOCKFILE));
+                        }
+
+                        /* the guest already present is kept until its replacement has been fetched and verified */
+                        if updating == true || stale == true
+                        {
+                            if settings.no_downloads == true
+                            {
+                                fatal_error(format!("Guest {} needs fetching afresh, which --skip-downloads doesn't allow", guest));
+                            }
+
+                            if let Err(e) = lockfile.remove(guest)
+                            {
+                                fatal_error(e);
+                            }
+                            refreshing.push(guest.to_string());
+                        }
+                    }
+
+                    /* fetch the ones that don't exist or need fetching afresh from their URLs, all at once.
+                       signed guests are checked against their signatures as they're fetched, so a signed guest
+                       that's present but not in the lockfile, and so can't be vouched for, is fetched again */
+                    let mut jobs = Vec::new();
+                    for (guest, g, path, built) in &selected
+                    {
+                        let unchecked = *built == false && g.url.is_some() && g.signature.is_some() && lockfile.get(guest).is_none();
+                        if Path::new(&path).exists() == false || refreshing.contains(guest) == true || unchecked == true
                         {
-                            Some(g) =>
+                            if let (Some(url), false) = (&g.url, settings.no_downloads)
                             {
-                                /* generate path name of guest image */
-                                let mut path = base.clone();
-                                path.push(&g.path);
-                                /* make sure a directory is present to house the guest */
-                                if let Err(e) = create_dir_all(&path)
+                                /* a fresh download must match the lockfile if the manifest doesn't give a hash */
+                                let mut g = (*g).clone();
+                                if let (None, Some(entry)) = (&g.sha256, lockfile.get(guest))
                                 {
-                                    fatal_error(format!("Can't ensure directory {} exists for guest {} ({})",
-                                        &path.to_str().unwrap(), &guest, e));
+                                    g.sha256 = Some(entry.sha256.clone());
                                 }
-                                path.push(&guest);
 
-                                /* if it doesn't exist, try fetching from its URL */
-                                if Path::new(&path).exists() == false
+                                jobs.push(fetch::FetchJob
                                 {
-                                    if let (Some(url), false) = (&g.url, settings.no_downloads)
-                                    {
-                                        if settings.verbose == true
-                                        {
-                                            println!("Downloading guest OS {}...", &g.description);
-                                        }
+                                    url: url.clone(),
+                                    label: guest.to_string(),
+                                    guest: g,
+                                    dest: path.clone(),
+                                    refresh: refreshing.contains(guest),
+                                    revalidate: false,
+                                    as_published: false
+                                });
+                            }
+                            else if unchecked == true
+                            {
+                                fatal_error(format!("Guest {} needs checking against its signature, which --skip-downloads doesn't allow", guest));
+                            }
+                            else
+                            {
+                                /* the load_file() will fail anyway but why not handle it here */
+                                fatal_error(format!("Can't find guest OS file {}", path.to_str().unwrap()));
+                            }
+                        }
+                        else if let (None, false, Some(_)) = (&g.url, *built, &g.signature)
+                        {
+                            /* a signed guest that isn't fetched from anywhere is checked as it is */
+                            if let Err(e) = fetcher.verify_signature(path, guest, g).await
+                            {
+                                fatal_error(e);
+                            }
+                        }
+                        else if let (Some(url), false, true) = (&g.url, *built, settings.refresh)
+                        {
+                            /* a changed guest is expected to differ from the lockfile */
+                            jobs.push(fetch::FetchJob
+                            {
+                                url: url.clone(),
+                                label: guest.to_string(),
+                                guest: (*g).clone(),
+                                dest: path.clone(),
+                                refresh: false,
+                                revalidate: true,
+                                as_published: false
+                            });
+                        }
+                    }
 
-                                        /* fetch the guest */
-                                        let data = match reqwest::get(url).await
-                                        {
-                                            Ok(response) => response.bytes().await,
-                                            Err(e) => fatal_error(format!("Can't fetch {} for {}: {}",
-                                                        &url, &guest, e))
-                                        };
+                    let (fetched, errors) = fetcher.fetch_guests(jobs).await;
+                    if errors.is_empty() == false
+                    {
+                        for error in &errors
+                        {
+                            eprintln!("mkdmfs error: {}", error);
+                        }
+                        fatal_error(format!("Failed to fetch {} guest OS images", errors.len()));
+                    }
 
-                                        /* and write it to storage */
-                                        let mut fh = match File::create(&path)
-                                        {
-                                            Ok(fh) => fh,
-                                            Err(e) => fatal_error(format!("Can't create {} for {}: {}",
-                                                                    &path.to_str().unwrap(), &guest, e))
-                                        };
+                    /* and include them in the image */
+                    for (guest, g, path, built) in selected
+                    {
+                        if settings.verbose == true
+                        {
+                            println!("Including guest OS {}...", &g.description);
+                        }
+
+                        /* dmfs only takes an object's data as bytes, so the guest has to be read in whole.
+                           read it just the once, and check the copy that goes into the image */
+                        let bytes = load_file(&path, settings.verbose);
 
-                                        let mut slice: &[u8] = data.as_ref().unwrap();
+                        /* check the guest is what we expect, unless we've just built it */
+                        if built == false
+                        {
+                            let digests = digest::hash_data(&bytes);
+                            if let Err(e) = verify_checksums(guest, g, &digests)
+                            {
+                                fatal_error(format!("Guest OS file {} failed verification: {}", path.to_str().unwrap(), e));
+                            }
 
-                                        if let Err(e) = io::copy(&mut slice, &mut fh)
+                            /* and that it agrees with the lockfile, or record it there if it's new */
+                            if let Some(url) = g.url.as_ref().filter(|url| fetcher.is_local(url) == false)
+                            {
+                                match lockfile.get(guest)
+                                {
+                                    Some(entry) => if entry.size != digests.size || entry.sha256 != digests.sha256
+                                    {
+                                        /* accept a guest that --refresh found had changed and replaced, but nothing else */
+                                        match (settings.refresh, fetched.get(guest))
                                         {
-                                            fatal_error(format!("Failed to write {} for {}: {}",
-                                                &path.to_str().unwrap(), &guest, e));
+                                            (true, Some(f)) if f.replaced == true =>
+                                            {
+                                                if settings.verbose == true
+                                                {
+                                                    println!("Guest OS {} has changed", &g.description);
+                                                }
+                                                if let Err(e) = lockfile.insert(guest, &f.source, &digests)
+                                                {
+                                                    fatal_error(e);
+                                                }
+                                            },
+                                            (_, _) => fatal_error(format!("Guest OS file {} doesn't match {}. Run the update subcommand to fetch it afresh",
+                                                        path.to_str().unwrap(), LOCKFILE))
                                         }
-                                    }
-                                    else
+                                    },
+                                    None =>
                                     {
-                                        /* the load_file() will fail anyway but why not handle it here */
-                                        fatal_error(format!("Can't find guest OS file {}", path.to_str().unwrap()));
+                                        let source = match fetched.get(guest)
+                                        {
+                                            Some(f) => &f.source,
+                                            None => url
+                                        };
+                                        if let Err(e) = lockfile.insert(guest, source, &digests)
+                                        {
+                                            fatal_error(e);
+                                        }
                                     }
                                 }
+                            }
+                        }
 
-                                if settings.verbose == true
-                                {
-                                    println!("Including guest OS {}...", &g.description);
-                                }
+                        /* work out how much RAM and how many CPUs to give the guest */
+                        let properties = match add_resources(None, guest, g.ram, g.cpus, &settings.config.defaults)
+                        {
+                            Ok(p) => p,
+                            Err(e) => fatal_error(e)
+                        };
 
-                                manifest.add(ManifestObject::new(
-                                    ManifestObjectType::GuestOS,
-                                    guest.clone(),
-                                    g.description.clone(),
-                                    ManifestObjectData::Bytes(load_file(&path, settings.verbose)),
-                                    None
-                                ));
-                            },
-                            None => fatal_error(format!("Guest {} required by target architecture {} not defined", guest, target_arch))
+                        manifest.add(ManifestObject::new(
+                            ManifestObjectType::GuestOS,
+                            guest.clone(),
+                            g.description.clone(),
+                            ManifestObjectData::Bytes(bytes),
+                            properties
+                        ));
+                    }
+
+                    if lockfile.is_changed() == true
+                    {
+                        if settings.verbose == true
+                        {
+                            println!("Updating {}", lockfile.path().display());
+                        }
+
+                        if let Err(e) = lockfile.save()
+                        {
+                            fatal_error(e);
                         }
                     }
                 }
@@ -494,7 +1133,7 @@ async fn main() -> Result<()>
     of.push(match settings.output_filename
     {
         Some(f) => f,
-        None => fatal_error(format!("No output filename specified"))
+        None => fatal_error(String::from("No output filename specified"))
     });
 
     /* create a file to write out the dmfs image */
@@ -517,6 +1156,131 @@ async fn main() -> Result<()>
     Ok(())
 }
 
+/* copy every guest fetched from a URL by any target into a vendor directory, so that
+   later builds can find them without the network. bails out if any guest can't be vendored
+   => settings = program settings
+      fetcher = fetcher to get the guests with
+      dir = vendor directory */
+async fn vendor_guests(settings: &Settings, fetcher: &Fetcher, dir: &Path)
+{
+    let available_guests = match &settings.config.guest
+    {
+        Some(hashtbl) => hashtbl,
+        None => return
+    };
+
+    let lockfile = match Lockfile::load(&settings.config_dir, settings.locked)
+    {
+        Ok(l) => l,
+        Err(e) => fatal_error(e)
+    };
+
+    /* gather up every guest used by every target, once */
+    let mut labels = Vec::new();
+    if let Some(targets) = &settings.config.target
+    {
+        for target in targets.values()
+        {
+            if let Some(guests) = &target.guests
+            {
+                for label in guests
+                {
+                    if labels.contains(label) == false
+                    {
+                        labels.push(label.clone());
+                    }
+                }
+            }
+        }
+    }
+    labels.sort();
+
+    let mut jobs = Vec::new();
+    for label in &labels
+    {
+        let g = match available_guests.get(label)
+        {
+            Some(g) => g,
+            None => fatal_error(format!("Guest {} required by a target architecture not defined", label))
+        };
+
+        /* guests without URLs are local already */
+        if let Some(url) = &g.url
+        {
+            let mut g = g.clone();
+            if let (None, Some(entry)) = (&g.sha256, lockfile.get(label))
+            {
+                g.sha256 = Some(entry.sha256.clone());
+            }
+
+            jobs.push(fetch::FetchJob
+            {
+                url: url.clone(),
+                label: label.clone(),
+                guest: g,
+                dest: fetch::vendored_pathname(&Some(dir.to_path_buf()), label).unwrap(),
+                refresh: false,
+                revalidate: false,
+                as_published: true
+            });
+        }
+    }
+
+    let guests_dir = dir.join("guests");
+    if let Err(e) = create_dir_all(&guests_dir)
+    {
+        fatal_error(format!("Can't create vendor directory {}: {}", guests_dir.display(), e));
+    }
+
+    let count = jobs.len();
+    let (_, errors) = fetcher.fetch_guests(jobs).await;
+    if errors.is_empty() == false
+    {
+        for error in &errors
+        {
+            eprintln!("mkdmfs error: {}", error);
+        }
+        fatal_error(format!("Failed to vendor {} guest OS images", errors.len()));
+    }
+
+    println!("Vendored {} guest OS images into {}", count, dir.display());
+}
+
+/* carry out a cache subcommand, bailing out if it fails
+   => cache = shared download cache
+      command = list, verify, or prune
+      all = true to remove every entry when pruning */
+fn manage_cache(cache: &DownloadCache, command: &String, all: bool)
+{
+    match command.as_str()
+    {
+        "list" => for entry in cache.entries()
+        {
+            println!("{} {}", entry.hash, entry.url);
+        },
+        "verify" =>
+        {
+            let errors = cache.verify();
+            for error in &errors
+            {
+                eprintln!("{}", error);
+            }
+
+            match errors.len()
+            {
+                0 => println!("All {} cached guest images verified", cache.entries().len()),
+                n => fatal_error(format!("{} cached guest images failed verification. Run the cache prune subcommand to remove them", n))
+            }
+        },
+        "prune" => match cache.prune(all)
+        {
+            Ok(removed) => println!("Removed {} entries from the download cache", removed),
+            Err(e) => fatal_error(e)
+        },
+        _ => fatal_error(format!("Unknown cache subcommand {}", command))
+    }
+}
+
 /* starting in the current working directory, check for the presence of the
    required config file, and if it's not there, check inside the parent.
    continue up the host file system tree until after hitting the root node.
@@ -553,13 +1317,139 @@ fn search_for_config(leafname: &str) -> Option<PathBuf>
     None
 }
 
+/* build a guest kernel image from source using buildroot, and copy the
+   resulting image into place. bails out if the build fails.
+   => base = manifest configuration file's directory
+      label = guest's label
+      guest = guest's configuration
+      tree = buildroot source tree, relative to base
+      dest = pathname to write the built guest kernel image
+      offline = true if the network isn't to be used
+      verbose = true to show buildroot's progress */
+fn build_guest(base: &PathBuf, label: &String, guest: &Guest, tree: &String, dest: &PathBuf, offline: bool, verbose: bool)
+{
+    /* buildroot downloads any sources missing from its download directory, and can't be told not to */
+    if offline == true
+    {
+        eprintln!("mkdmfs warning: buildroot may use the network to build guest {}, despite --offline. \
+                   Use --skip-buildroot to avoid this", label);
+    }
+
+    let defconfig = match &guest.defconfig
+    {
+        Some(d) => d,
+        None => fatal_error(format!("Guest {} needs a defconfig to build it with buildroot", label))
+    };
+
+    /* make runs in the buildroot tree, and would take relative pathnames, such as those from a relative
+       manifest pathname, relative to the tree rather than to here */
+    let base = match env::current_dir()
+    {
+        Ok(cwd) => cwd.join(base),
+        Err(e) => fatal_error(format!("Can't get current working directory to build guest {}: {}", label, e))
+    };
+
+    let mut tree_dir = base.clone();
+    tree_dir.push(tree);
+
+    let mut output_dir = tree_dir.clone();
+    output_dir.push("output");
+    output_dir.push(label);
+
+    /* the defconfig is either a file relative to the manifest, or a config name known to buildroot */
+    let mut defconfig_file = base.clone();
+    defconfig_file.push(defconfig);
+    let configure_target = match defconfig_file.is_file()
+    {
+        true => vec![format!("BR2_DEFCONFIG={}", defconfig_file.display()), String::from("defconfig")],
+        false => vec![defconfig.clone()]
+    };
+
+    if verbose == true
+    {
+        println!("Building guest OS {} using buildroot in {}...", &guest.description, output_dir.display());
+    }
+
+    /* configure and then build the guest */
+    for targets in [configure_target, Vec::new()]
+    {
+        let mut cmd = Command::new("make");
+        cmd.arg("-C").arg(&tree_dir).arg(format!("O={}", output_dir.display())).args(&targets);
+
+        let output = match verbose
+        {
+            /* let the user see the build progress */
+            true => cmd.status().map(|status| (status, Vec::new())),
+
+            /* keep quiet unless something goes wrong */
+            false => cmd.output().map(|output| (output.status, output.stderr))
+        };
+
+        match output
+        {
+            Ok((status, _)) if status.success() == true => (),
+            Ok((status, stderr)) =>
+            {
+                eprint!("{}", String::from_utf8_lossy(&stderr));
+                fatal_error(format!("Buildroot failed to build guest {} ({})", label, status));
+            },
+            Err(e) => fatal_error(format!("Can't run make to build guest {}: {}", label, e))
+        }
+    }
+
+    /* pick up the built kernel image */
+    let mut image = output_dir.clone();
+    image.push("images");
+    image.push(match &guest.image
+    {
+        Some(i) => i.as_str(),
+        None => BUILDROOT_IMAGE
+    });
+
+    /* dest may be hard-linked to the download cache or a vendored guest, so replace it rather than write into it */
+    let temp = fetch::temp_pathname(dest);
+    if let Err(e) = copy(&image, &temp).and_then(|_| rename(&temp, dest))
+    {
+        let _ = remove_file(&temp);
+        fatal_error(format!("Can't copy built guest image {} to {}: {}", image.display(), dest.display(), e));
+    }
+}
+
+/* check a guest's image against the hashes, if any, given in its configuration
+   => label = guest's label
+      guest = guest's configuration
+      digests = size and hashes of the guest's kernel image
+   <= returns Ok if the image matches its hashes, or an error message describing the mismatch */
+fn verify_checksums(label: &String, guest: &Guest, digests: &digest::Digests) -> std::result::Result<(), String>
+{
+    if let Some(expected) = &guest.sha256
+    {
+        let found = &digests.sha256;
+        if *found != expected.trim().to_lowercase()
+        {
+            return Err(format!("SHA-256 mismatch for guest {}: expected {}, found {}", label, expected, found));
+        }
+    }
+
+    if let Some(expected) = &guest.sha512
+    {
+        let found = &digests.sha512;
+        if *found != expected.trim().to_lowercase()
+        {
+            return Err(format!("SHA-512 mismatch for guest {}: expected {}, found {}", label, expected, found));
+        }
+    }
+
+    Ok(())
+}
+
 /* load a file from the host file system into memory.
 bails out if it can't read the file */
 fn load_file(path: &PathBuf, verbose: bool) -> Vec<u8>
 {
     let mut buffer = Vec::new();
 
-    let mut fh = match File::open(&path)
+    let mut fh = match File::open(path)
     {
         Ok(fh) => fh,
         Err(e) => fatal_error(format!("Can't open file {}: {}", path.display(), e))
@@ -577,11 +1467,48 @@ fn load_file(path: &PathBuf, verbose: bool) -> Vec<u8>
     buffer
 }
 
+/* add a capsule's RAM and CPU allocation to its list of properties, falling back to
+   the manifest config file's defaults if the capsule doesn't specify its own
+   => properties = object's existing properties, if any
+      name = name of the guest or service, for error messages
+      ram, cpus = capsule's own allocation, if specified
+      defaults = manifest config file's defaults
+   <= returns the object's properties including its allocation, or None if there are none,
+      or an error message if an allocation is zero or too large */
+fn add_resources(properties: Option<Vec<String>>, name: &String, ram: Option<usize>, cpus: Option<usize>, defaults: &Defaults) -> std::result::Result<Option<Vec<String>>, String>
+{
+    let mut properties = properties.unwrap_or_default();
+
+    if let Some(ram) = ram.or(defaults.ram)
+    {
+        if ram == 0 || ram > RAM_MAX
+        {
+            return Err(format!("RAM allocation of {} MB for {} must be between 1 and {} MB", ram, name, RAM_MAX));
+        }
+        properties.push(format!("ram={}", ram));
+    }
+
+    if let Some(cpus) = cpus.or(defaults.cpus)
+    {
+        if cpus == 0 || cpus > CPUS_MAX
+        {
+            return Err(format!("CPU allocation of {} cores for {} must be between 1 and {}", cpus, name, CPUS_MAX));
+        }
+        properties.push(format!("cpus={}", cpus));
+    }
+
+    match properties.len()
+    {
+        0 => Ok(None),
+        _ => Ok(Some(properties))
+    }
+}
+
 /* translate a full target architecture into a base architecture */
-fn get_base_arch(full_target: &String) -> Option<String>
+fn get_base_arch(full_target: &str) -> Option<String>
 {
     let re = Regex::new(r"(?P<arch>riscv|aarch64|arm|powerpc64|x86_64){1}").unwrap();
-    let matches = re.captures(&full_target);
+    let matches = re.captures(full_target);
     if matches.is_none() == true
     {
         return None; /* unknown architecture */
@@ -596,4 +1523,125 @@ fn fatal_error(msg: String) -> !
     /* ignores the verbose setting */
     eprintln!("mkdmfs error: {}", msg);
     exit(1);
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    static ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    static ABC_SHA512: &str = concat!("ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a",
+                                      "2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f");
+
+    fn guest(hashes: &str) -> Guest
+    {
+        toml::from_str(format!("path = \"guests\"\ndescription = \"test guest\"\n{}", hashes).as_str()).unwrap()
+    }
+
+    #[test]
+    fn checks_guests_against_their_hashes()
+    {
+        let label = String::from("g");
+        let digests = digest::hash_data(b"abc");
+
+        assert!(verify_checksums(&label, &guest(""), &digests).is_ok());
+        assert!(verify_checksums(&label, &guest(format!("sha256 = \"{}\"", ABC_SHA256).as_str()), &digests).is_ok());
+        assert!(verify_checksums(&label, &guest(format!("sha512 = \"{}\"", ABC_SHA512).as_str()), &digests).is_ok());
+        assert!(verify_checksums(&label, &guest(format!("sha256 = \"{}\"\nsha512 = \"{}\"", ABC_SHA256, ABC_SHA512).as_str()), &digests).is_ok());
+
+        /* both hashes must match if both are given */
+        let other = digest::hash_data(b"abd");
+        let e = verify_checksums(&label, &guest(format!("sha256 = \"{}\"", other.sha256).as_str()), &digests).unwrap_err();
+        assert!(e.starts_with("SHA-256 mismatch for guest g"));
+        let e = verify_checksums(&label, &guest(format!("sha256 = \"{}\"\nsha512 = \"{}\"", ABC_SHA256, other.sha512).as_str()), &digests).unwrap_err();
+        assert!(e.starts_with("SHA-512 mismatch for guest g"));
+    }
+
+    #[test]
+    fn ignores_case_and_whitespace_of_expected_hashes()
+    {
+        let label = String::from("g");
+        let digests = digest::hash_data(b"abc");
+
+        let hashes = format!("sha256 = \" {} \"\nsha512 = \"{}\\n\"", ABC_SHA256.to_uppercase(), ABC_SHA512.to_uppercase());
+        assert!(verify_checksums(&label, &guest(hashes.as_str()), &digests).is_ok());
+
+        /* but not a truncated hash */
+        let hashes = format!("sha256 = \"{}\"", &ABC_SHA256[..63]);
+        assert!(verify_checksums(&label, &guest(hashes.as_str()), &digests).is_err());
+    }
+
+    fn defaults(ram: Option<usize>, cpus: Option<usize>) -> Defaults
+    {
+        Defaults { arch: None, quality: None, outfile: None, ram, cpus }
+    }
+
+    #[test]
+    fn allocates_resources_from_capsule_or_defaults()
+    {
+        let name = String::from("g");
+
+        /* with no allocation anywhere, the hypervisor chooses */
+        assert!(add_resources(None, &name, None, None, &defaults(None, None)).unwrap().is_none());
+        assert_eq!(add_resources(Some(vec![String::from("console_write")]), &name, None, None, &defaults(None, None)).unwrap(),
+                   Some(vec![String::from("console_write")]));
+
+        /* the capsule's own allocation overrides the defaults, each on its own */
+        assert_eq!(add_resources(None, &name, None, None, &defaults(Some(128), Some(2))).unwrap(),
+                   Some(vec![String::from("ram=128"), String::from("cpus=2")]));
+        assert_eq!(add_resources(None, &name, Some(256), None, &defaults(Some(128), Some(2))).unwrap(),
+                   Some(vec![String::from("ram=256"), String::from("cpus=2")]));
+        assert_eq!(add_resources(Some(vec![String::from("console_write")]), &name, None, Some(4), &defaults(Some(128), None)).unwrap(),
+                   Some(vec![String::from("console_write"), String::from("ram=128"), String::from("cpus=4")]));
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_resources()
+    {
+        let name = String::from("g");
+        let none = defaults(None, None);
+
+        assert!(add_resources(None, &name, Some(0), None, &none).is_err());
+        assert!(add_resources(None, &name, Some(RAM_MAX + 1), None, &none).is_err());
+        assert!(add_resources(None, &name, Some(RAM_MAX), None, &none).is_ok());
+        assert!(add_resources(None, &name, None, Some(0), &none).is_err());
+        assert!(add_resources(None, &name, None, Some(CPUS_MAX + 1), &none).is_err());
+        assert!(add_resources(None, &name, None, Some(CPUS_MAX), &none).is_ok());
+
+        /* bad defaults are caught too, but only if they're used */
+        assert!(add_resources(None, &name, None, None, &defaults(Some(0), None)).is_err());
+        assert!(add_resources(None, &name, Some(1), None, &defaults(Some(0), None)).is_ok());
+    }
+
+    /* the relative pathname of a directory from the current working directory */
+    fn relative(dir: &Path) -> PathBuf
+    {
+        let cwd = env::current_dir().unwrap();
+        let mut path: PathBuf = cwd.components().skip(1).map(|_| "..").collect();
+        path.push(dir.strip_prefix("/").unwrap());
+        path
+    }
+
+    #[test]
+    fn builds_guests_from_a_relative_manifest_pathname()
+    {
+        let dir = crate::testutil::scratch("buildroot-relative");
+        let tree = dir.join("buildroot");
+        create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join("Makefile"), concat!(
+            "all:\n\tmkdir -p $(O)/images && cat $(O)/.config > $(O)/images/Image\n",
+            "defconfig:\n\tmkdir -p $(O) && cp $(BR2_DEFCONFIG) $(O)/.config\n")).unwrap();
+        std::fs::write(dir.join("guest_defconfig"), "kernel\n").unwrap();
+
+        let guest: Guest = toml::from_str("path = \"guests\"\ndescription = \"test guest\"\ndefconfig = \"guest_defconfig\"\n").unwrap();
+        let base = relative(&dir);
+        assert!(base.is_relative());
+
+        let dest = dir.join("g");
+        build_guest(&base, &String::from("g"), &guest, &String::from("buildroot"), &dest, false, false);
+        assert_eq!(std::fs::read(&dest).unwrap(), b"kernel\n");
+        assert!(tree.join("output").join("g").join("images").join("Image").is_file());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
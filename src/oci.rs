@@ -0,0 +1,671 @@
+/* Resolve guest OS images published as OCI artifacts
+ *
+ * A guest's url can be oci://<registry>/<repository>:<tag> or
+ * oci://<registry>/<repository>@<digest>. The registry is asked for the image
+ * manifest, following a single-entry index if need be, and the guest's layer is
+ * picked out of it: the one whose title annotation or digest matches the guest's
+ * layer setting, or the only layer if there's just one. The fetcher then downloads
+ * the layer's blob like any other file, and checks it against its digest.
+ *
+ * Registries are reached over HTTPS, except for those on localhost, which are
+ * reached over plain HTTP as a local registry:2 stand-in usually is. Registries
+ * that want a bearer token are asked for a pull token by the token service they
+ * name. The token service must pass the download policy, and the manifest's
+ * token for the registry's host is only passed on to it if it's on the same host
+ * and reached over HTTPS. Otherwise an anonymous pull token is asked for. No
+ * token is ever sent over plain HTTP, so local registries can't require one.
+ *
+ * Manifests and token responses are held in memory, so they're limited in size
+ * by both the download policy and METADATA_MAX. Registries and token services
+ * get as long to respond as any other server before they're given up on.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::time::timeout;
+
+use serde_derive::Deserialize;
+use reqwest::{Client, RequestBuilder, Response, StatusCode, Url};
+use reqwest::header::{ACCEPT, WWW_AUTHENTICATE};
+
+use super::digest::{self, Digests};
+use super::policy::DownloadPolicy;
+use super::auth;
+
+/* URL scheme for guests in OCI registries */
+pub static OCI_SCHEME: &str = "oci://";
+
+/* manifest types we understand */
+static MANIFEST_TYPES: &str = "application/vnd.oci.image.manifest.v1+json, \
+                               application/vnd.docker.distribution.manifest.v2+json, \
+                               application/vnd.oci.image.index.v1+json, \
+                               application/vnd.docker.distribution.manifest.list.v2+json";
+
+/* annotation holding a layer's filename, as set by tools such as oras */
+static TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+
+/* largest manifest or token response to accept, whatever the download policy allows */
+static METADATA_MAX: u64 = 4 * 1024 * 1024;
+
+/* registries that are reached over plain HTTP */
+static LOCAL_REGISTRIES: [&str; 3] = [ "localhost", "127.0.0.1", "[::1]" ];
+
+/* a guest's location in a registry */
+pub struct Reference
+{
+    registry: String,
+    repository: String,
+    reference: String /* tag or digest */
+}
+
+/* where to fetch a guest's layer from, and what it should hash to */
+pub struct Blob
+{
+    pub url: String,
+    pub digest: String,
+    pub size: u64,
+    pub token: Option<String> /* registry token needed to fetch the blob, if any */
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor
+{
+    digest: String,
+    size: u64,
+    annotations: Option<HashMap<String, String>>
+}
+
+/* an image manifest has layers, an index has manifests */
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest
+{
+    media_type: Option<String>,
+    layers: Option<Vec<Descriptor>>,
+    manifests: Option<Vec<Descriptor>>
+}
+
+#[derive(Deserialize)]
+struct TokenResponse
+{
+    token: Option<String>,
+    access_token: Option<String>
+}
+
+/* return true if a guest URL refers to an OCI registry */
+pub fn is_oci(url: &str) -> bool
+{
+    url.starts_with(OCI_SCHEME)
+}
+
+impl Reference
+{
+    /* parse oci://<registry>/<repository>:<tag> or oci://<registry>/<repository>@<digest>
+       => url = guest URL to parse
+       <= returns the reference, or an error message */
+    pub fn parse(url: &String) -> Result<Reference, String>
+    {
+        let rest = match url.strip_prefix(OCI_SCHEME)
+        {
+            Some(r) => r,
+            None => return Err(format!("{} isn't an {} URL", url, OCI_SCHEME))
+        };
+
+        let (registry, path) = match rest.find('/')
+        {
+            Some(i) if i > 0 => (&rest[..i], &rest[i + 1..]),
+            _ => return Err(format!("No registry given in {}", url))
+        };
+
+        /* a digest is introduced by @, a tag by the last : after the last / */
+        let (repository, reference) = match path.find('@')
+        {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None =>
+            {
+                let leaf = path.rfind('/').map(|i| i + 1).unwrap_or(0);
+                match path[leaf..].rfind(':')
+                {
+                    Some(i) => (&path[..leaf + i], &path[leaf + i + 1..]),
+                    None => (path, "latest")
+                }
+            }
+        };
+
+        if repository.is_empty() || reference.is_empty()
+        {
+            return Err(format!("No repository or tag given in {}", url));
+        }
+
+        Ok(Reference
+        {
+            registry: registry.to_string(),
+            repository: repository.to_string(),
+            reference: reference.to_string()
+        })
+    }
+
+    /* return the registry's host name, without any port number */
+    fn host(&self) -> &str
+    {
+        match self.registry.rfind(':')
+        {
+            Some(i) if self.registry.ends_with(']') == false => &self.registry[..i],
+            _ => self.registry.as_str()
+        }
+    }
+
+    /* return the base URL of the registry's API for this repository */
+    pub fn base_url(&self) -> String
+    {
+        let scheme = match LOCAL_REGISTRIES.contains(&self.host())
+        {
+            true => "http",
+            false => "https"
+        };
+
+        format!("{}://{}/v2/{}", scheme, self.registry, self.repository)
+    }
+
+    /* return the reference as a URL, for error messages */
+    fn describe(&self) -> String
+    {
+        match self.reference.contains(':')
+        {
+            true => format!("{}{}/{}@{}", OCI_SCHEME, self.registry, self.repository, self.reference),
+            false => format!("{}{}/{}:{}", OCI_SCHEME, self.registry, self.repository, self.reference)
+        }
+    }
+}
+
+/* find the blob holding a guest's layer
+   => client = HTTP client to use
+      reference = guest's location in the registry
+      layer = title annotation or digest of the layer to use, or None to use the only layer
+      token = bearer token the manifest provides for the registry's host, if any
+      policy = download policy that token services and response sizes must pass
+      read_timeout = how long to wait for a response, or more of one, before giving up
+      label = guest's label, for error messages
+   <= returns the layer's blob, or an error message */
+pub async fn resolve(client: &Client, reference: &Reference, layer: &Option<String>, token: Option<String>,
+                     policy: &DownloadPolicy, read_timeout: Duration, label: &String) -> Result<Blob, String>
+{
+    let base = reference.base_url();
+    let mut token = token;
+
+    /* follow an index to its manifest, but no further */
+    let mut manifest_ref = reference.reference.clone();
+    let mut manifest = None;
+    for _ in 0..2
+    {
+        let url = format!("{}/manifests/{}", base, manifest_ref);
+        let body = get(client, &url, &mut token, reference, policy, read_timeout, label).await?;
+
+        /* content fetched by digest must match that digest */
+        if manifest_ref.contains(':') == true
+        {
+            check_digest(&digest::hash_data(&body), &manifest_ref, &url)?;
+        }
+
+        let parsed: Manifest = match serde_json::from_slice(&body)
+        {
+            Ok(m) => m,
+            Err(e) => return Err(format!("Can't parse manifest {} for {}: {}", url, label, e))
+        };
+
+        match (&parsed.layers, &parsed.manifests)
+        {
+            (Some(_), _) =>
+            {
+                manifest = Some(parsed);
+                break;
+            },
+            (None, Some(entries)) if entries.len() == 1 => manifest_ref = entries[0].digest.clone(),
+            (None, Some(entries)) =>
+                return Err(format!("{} for {} is an index of {} manifests; name the one to use by digest",
+                           reference.describe(), label, entries.len())),
+            (None, None) =>
+                return Err(format!("Manifest {} for {} has no layers ({})", url, label,
+                           parsed.media_type.as_deref().unwrap_or("unknown type")))
+        }
+    }
+
+    let layers = match manifest.and_then(|m| m.layers)
+    {
+        Some(l) => l,
+        None => return Err(format!("{} for {} is an index of indexes, which isn't supported", reference.describe(), label))
+    };
+
+    let chosen = match layer
+    {
+        Some(wanted) => layers.into_iter().find(|l| l.digest == *wanted || title(l) == Some(wanted)),
+        None if layers.len() == 1 => layers.into_iter().next(),
+        None =>
+        {
+            let titles: Vec<String> = layers.iter().map(|l| title(l).unwrap_or(&l.digest).clone()).collect();
+            return Err(format!("{} for {} has {} layers ({}); set guest.{}.layer to pick one",
+                       reference.describe(), label, titles.len(), titles.join(", "), label));
+        }
+    };
+
+    match chosen
+    {
+        Some(l) =>
+        {
+            Ok(Blob
+            {
+                url: format!("{}/blobs/{}", base, l.digest),
+                digest: l.digest,
+                size: l.size,
+                token
+            })
+        },
+        None => Err(format!("{} for {} has no layer {}", reference.describe(), label, layer.as_ref().unwrap()))
+    }
+}
+
+/* check content's hashes against an OCI digest, in the form <algorithm>:<hex>
+   => digests = size and hashes of the content to check
+      digest = digest it should have
+      url = where the content came from, for error messages
+   <= returns Ok if it matches, or an error message */
+pub fn check_digest(digests: &Digests, digest: &String, url: &String) -> Result<(), String>
+{
+    let found = match digest.split(':').next()
+    {
+        Some("sha256") => format!("sha256:{}", digests.sha256),
+        Some("sha512") => format!("sha512:{}", digests.sha512),
+        _ => return Err(format!("Unsupported digest {} for {}", digest, url))
+    };
+
+    match found == digest.to_lowercase()
+    {
+        true => Ok(()),
+        false => Err(format!("{} has digest {} but expected {}", url, found, digest))
+    }
+}
+
+/* return a layer's title annotation, if any */
+fn title(layer: &Descriptor) -> Option<&String>
+{
+    layer.annotations.as_ref().and_then(|a| a.get(TITLE_ANNOTATION))
+}
+
+/* fetch a manifest or index from a registry, getting a pull token first if the registry asks for one
+   => client = HTTP client to use
+      url = what to fetch
+      token = bearer token to send, updated if a new one is obtained
+      reference = guest's location in the registry
+      policy = download policy that token services and response sizes must pass
+      read_timeout = how long to wait for a response, or more of one, before giving up
+      label = guest's label, for error messages
+   <= returns the response body, or an error message */
+async fn get(client: &Client, url: &String, token: &mut Option<String>, reference: &Reference,
+             policy: &DownloadPolicy, read_timeout: Duration, label: &String) -> Result<Vec<u8>, String>
+{
+    for attempt in 0..2
+    {
+        let request = authorize(client.get(url).header(ACCEPT, MANIFEST_TYPES), url, token)?;
+        let response = match timeout(read_timeout, request.send()).await
+        {
+            Ok(Ok(r)) => r,
+            Ok(Err(e)) => return Err(format!("Can't fetch {} for {}: {}", url, label, e)),
+            Err(_) => return Err(format!("Timed out waiting for {} for {}", url, label))
+        };
+
+        match response.status()
+        {
+            s if s.is_success() == true => return read_body(response, url, policy, read_timeout, label).await,
+            StatusCode::UNAUTHORIZED if attempt == 0 =>
+            {
+                let challenge = match response.headers().get(WWW_AUTHENTICATE).and_then(|h| h.to_str().ok())
+                {
+                    Some(c) => c.to_string(),
+                    None => return Err(format!("Registry refused {} for {} without saying how to authenticate", url, label))
+                };
+                *token = Some(pull_token(client, &challenge, token, reference, policy, read_timeout, label).await?);
+            },
+            s => return Err(format!("Registry returned {} for {} for {}", s, url, label))
+        }
+    }
+
+    Err(format!("Registry refused {} for {} even with a pull token", url, label))
+}
+
+/* ask a registry's token service for a pull token, as directed by its WWW-Authenticate challenge
+   => client = HTTP client to use
+      challenge = value of the registry's WWW-Authenticate header
+      token = bearer token sent to the registry so far, if any, passed on to the token service only if it's
+              the registry's own host over HTTPS
+      reference = guest's location in the registry
+      policy = download policy the token service and its response must pass
+      read_timeout = how long to wait for a response, or more of one, before giving up
+      label = guest's label, for error messages
+   <= returns the token, or an error message */
+async fn pull_token(client: &Client, challenge: &String, token: &Option<String>, reference: &Reference,
+                    policy: &DownloadPolicy, read_timeout: Duration, label: &String) -> Result<String, String>
+{
+    let params = match challenge.strip_prefix("Bearer ").or(challenge.strip_prefix("bearer "))
+    {
+        Some(p) => parse_challenge(p),
+        None => return Err(format!("Registry {} wants unsupported authentication for {}: {}", reference.registry, label, challenge))
+    };
+
+    let realm = match params.get("realm")
+    {
+        Some(r) => r,
+        None => return Err(format!("Registry {} didn't say where to get a token for {}", reference.registry, label))
+    };
+
+    let mut url = match Url::parse(realm)
+    {
+        Ok(u) => u,
+        Err(e) => return Err(format!("Registry {} gave a bad token service URL {} for {}: {}", reference.registry, realm, label, e))
+    };
+
+    {
+        let mut query = url.query_pairs_mut();
+        if let Some(service) = params.get("service")
+        {
+            query.append_pair("service", service);
+        }
+        match params.get("scope")
+        {
+            Some(scope) => query.append_pair("scope", scope),
+            None => query.append_pair("scope", format!("repository:{}:pull", reference.repository).as_str())
+        };
+    }
+
+    /* the registry decides where its token service is, so it gets no more trust than the registry itself */
+    policy.check_url(url.as_str())?;
+    let token = match trusted_realm(&url, reference)
+    {
+        true => token,
+        false => &None
+    };
+
+    let response = match timeout(read_timeout, authorize(client.get(url.as_str()), url.as_str(), token)?.send()).await
+    {
+        Ok(Ok(r)) => r,
+        Ok(Err(e)) => return Err(format!("Can't get token from {} for {}: {}", realm, label, e)),
+        Err(_) => return Err(format!("Timed out waiting for a token from {} for {}", realm, label))
+    };
+
+    if response.status().is_success() == false
+    {
+        return Err(format!("Token service {} returned {} for {}", realm, response.status(), label));
+    }
+
+    let body = read_body(response, realm, policy, read_timeout, label).await?;
+
+    match serde_json::from_slice::<TokenResponse>(&body)
+    {
+        Ok(TokenResponse { token: Some(t), .. }) | Ok(TokenResponse { access_token: Some(t), .. }) => Ok(t),
+        Ok(_) => Err(format!("Token service {} didn't return a token for {}", realm, label)),
+        Err(e) => Err(format!("Can't parse token from {} for {}: {}", realm, label, e))
+    }
+}
+
+/* decide whether a token service can be trusted with the registry's token: it must be on the
+   registry's own host, and reached over HTTPS so the token can't be read on the way */
+fn trusted_realm(url: &Url, reference: &Reference) -> bool
+{
+    let host = reference.host().trim_start_matches('[').trim_end_matches(']');
+    url.scheme() == "https" && url.host_str().map(|h| h.trim_start_matches('[').trim_end_matches(']').eq_ignore_ascii_case(host)) == Some(true)
+}
+
+/* read a manifest or token response, refusing to hold more than the download policy or METADATA_MAX allows
+   => response = response to read
+      url = where the response came from, for error messages
+      policy = download policy setting the size limit
+      read_timeout = how long to wait for more of the response before giving up
+      label = guest's label, for error messages
+   <= returns the body, or an error message */
+async fn read_body(response: Response, url: &str, policy: &DownloadPolicy, read_timeout: Duration, label: &String) -> Result<Vec<u8>, String>
+{
+    let check = |size: u64| match size > METADATA_MAX
+    {
+        true => Err(format!("{} for {} is larger than the {} byte limit on registry responses", url, label, METADATA_MAX)),
+        false => policy.check_size(size, url)
+    };
+
+    if let Some(length) = response.content_length()
+    {
+        check(length)?;
+    }
+
+    let mut response = response;
+    let mut body = Vec::new();
+    loop
+    {
+        match timeout(read_timeout, response.chunk()).await
+        {
+            Ok(Ok(Some(chunk))) =>
+            {
+                body.extend_from_slice(&chunk);
+                check(body.len() as u64)?;
+            },
+            Ok(Ok(None)) => return Ok(body),
+            Ok(Err(e)) => return Err(format!("Failed to download {} for {}: {}", url, label, e)),
+            Err(_) => return Err(format!("Timed out downloading {} for {}", url, label))
+        }
+    }
+}
+
+/* add a bearer token to a request, if there is one
+   => request = request to add the token to
+      url = URL the request is for
+      token = bearer token to send, if any
+   <= returns the request, or an error message if the token can't be sent to the URL */
+fn authorize(request: RequestBuilder, url: &str, token: &Option<String>) -> Result<RequestBuilder, String>
+{
+    match token
+    {
+        Some(t) => auth::bearer(request, url, t),
+        None => Ok(request)
+    }
+}
+
+/* split a challenge's key="value" parameters into a table */
+fn parse_challenge(params: &str) -> HashMap<String, String>
+{
+    let mut table = HashMap::new();
+    let mut rest = params.trim();
+
+    while let Some(eq) = rest.find('=')
+    {
+        let key = rest[..eq].trim().trim_start_matches(',').trim().to_string();
+        rest = &rest[eq + 1..];
+
+        let value = match rest.strip_prefix('"')
+        {
+            Some(quoted) =>
+            {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                let value = quoted[..end].to_string();
+                rest = &quoted[min(end + 1, quoted.len())..];
+                value
+            },
+            None =>
+            {
+                let end = rest.find(',').unwrap_or(rest.len());
+                let value = rest[..end].trim().to_string();
+                rest = &rest[end..];
+                value
+            }
+        };
+
+        table.insert(key, value);
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use crate::testutil::{response, StandIn};
+
+    fn parse(url: &str) -> Result<Reference, String>
+    {
+        Reference::parse(&url.to_string())
+    }
+
+    /* ask a stand-in registry for the given layer of diosix/linux:6.1, giving up after a second */
+    async fn resolve_from(registry: &StandIn, layer: Option<&str>) -> Result<Blob, String>
+    {
+        let reference = parse(format!("oci://{}/diosix/linux:6.1", registry.addr).as_str()).unwrap();
+        resolve(&Client::new(), &reference, &layer.map(|l| l.to_string()), None, &DownloadPolicy::new(&None),
+                Duration::from_secs(1), &String::from("g")).await
+    }
+
+    /* an image manifest of layers, each given as its title and contents */
+    fn manifest(layers: &[(&str, &[u8])]) -> String
+    {
+        let layers: Vec<String> = layers.iter().map(|(title, data)| format!(
+            r#"{{"mediaType":"application/octet-stream","digest":"sha256:{}","size":{},"annotations":{{"{}":"{}"}}}}"#,
+            digest::hash_data(data).sha256, data.len(), TITLE_ANNOTATION, title)).collect();
+        format!(r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","layers":[{}]}}"#, layers.join(","))
+    }
+
+    /* an index of a single manifest */
+    fn index(manifest: &str, digest: &str) -> String
+    {
+        format!(r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[{{"digest":"sha256:{}","size":{}}}]}}"#,
+                digest, manifest.len())
+    }
+
+    #[test]
+    fn parses_references()
+    {
+        let r = parse("oci://ghcr.io/diosix/guests/linux:6.1").unwrap();
+        assert_eq!((r.registry.as_str(), r.repository.as_str(), r.reference.as_str()), ("ghcr.io", "diosix/guests/linux", "6.1"));
+        assert_eq!(r.base_url(), "https://ghcr.io/v2/diosix/guests/linux");
+
+        /* a port isn't a tag */
+        let r = parse("oci://localhost:5000/linux").unwrap();
+        assert_eq!((r.registry.as_str(), r.repository.as_str(), r.reference.as_str()), ("localhost:5000", "linux", "latest"));
+        assert_eq!(r.base_url(), "http://localhost:5000/v2/linux");
+
+        let r = parse("oci://[::1]:5000/linux:v1").unwrap();
+        assert_eq!((r.host(), r.reference.as_str()), ("[::1]", "v1"));
+        assert_eq!(r.base_url(), "http://[::1]:5000/v2/linux");
+
+        let r = parse("oci://registry.example.com:8443/linux@sha256:abcd").unwrap();
+        assert_eq!((r.host(), r.repository.as_str(), r.reference.as_str()), ("registry.example.com", "linux", "sha256:abcd"));
+        assert_eq!(r.describe(), "oci://registry.example.com:8443/linux@sha256:abcd");
+
+        assert!(parse("https://ghcr.io/linux:v1").is_err());
+        assert!(parse("oci:///linux:v1").is_err());
+        assert!(parse("oci://ghcr.io").is_err());
+        assert!(parse("oci://ghcr.io/:v1").is_err());
+        assert!(parse("oci://ghcr.io/linux:").is_err());
+        assert!(parse("oci://ghcr.io/linux@").is_err());
+    }
+
+    #[test]
+    fn parses_challenges()
+    {
+        let table = parse_challenge(r#"realm="https://auth.example.com/token",service="registry.example.com", scope="repository:linux:pull,push""#);
+        assert_eq!(table.len(), 3);
+        assert_eq!(table["realm"], "https://auth.example.com/token");
+        assert_eq!(table["service"], "registry.example.com");
+        assert_eq!(table["scope"], "repository:linux:pull,push");
+
+        let table = parse_challenge(r#" realm=https://auth.example.com/token , error="insufficient_scope" "#);
+        assert_eq!(table["realm"], "https://auth.example.com/token");
+        assert_eq!(table["error"], "insufficient_scope");
+
+        /* an unterminated quote runs to the end */
+        assert_eq!(parse_challenge(r#"realm="https://auth"#)["realm"], "https://auth");
+        assert!(parse_challenge("").is_empty());
+    }
+
+    #[test]
+    fn trusts_only_the_registrys_own_host_over_https()
+    {
+        let r = parse("oci://Registry.example.com:8443/linux:v1").unwrap();
+        let trusted = |url: &str| trusted_realm(&Url::parse(url).unwrap(), &r);
+
+        assert!(trusted("https://registry.example.com/token") == true);
+        assert!(trusted("https://REGISTRY.example.com:9000/token") == true);
+        assert!(trusted("http://registry.example.com/token") == false);
+        assert!(trusted("https://auth.example.com/token") == false);
+        assert!(trusted("https://registry.example.com.evil.org/token") == false);
+
+        let r = parse("oci://[::1]:5000/linux").unwrap();
+        assert!(trusted_realm(&Url::parse("https://[::1]/token").unwrap(), &r) == true);
+    }
+
+    #[tokio::test]
+    async fn follows_an_index_and_picks_layers_by_title()
+    {
+        let layers = manifest(&[("Image", b"kernel"), ("initrd", b"ramdisk")]);
+        let hash = digest::hash_data(layers.as_bytes()).sha256;
+        let registry = StandIn::start(vec![
+            response("200 OK", &[], index(&layers, &hash).as_bytes()),
+            response("200 OK", &[], layers.as_bytes())
+        ]);
+
+        let blob = resolve_from(&registry, Some("initrd")).await.unwrap();
+        assert_eq!(blob.url, format!("http://{}/v2/diosix/linux/blobs/sha256:{}", registry.addr, digest::hash_data(b"ramdisk").sha256));
+        assert_eq!(blob.size, 7);
+        assert!(blob.token.is_none());
+
+        let requests = registry.requests();
+        assert!(requests[0].starts_with("get /v2/diosix/linux/manifests/6.1 ") && requests[0].contains("application/vnd.oci.image.index.v1+json"));
+        assert!(requests[1].starts_with(format!("get /v2/diosix/linux/manifests/sha256:{} ", hash).as_str()));
+
+        /* a manifest fetched by digest must match it, and a layer must be picked if there's more than one */
+        let registry = StandIn::start(vec![
+            response("200 OK", &[], index(&layers, &digest::hash_data(b"other").sha256).as_bytes()),
+            response("200 OK", &[], layers.as_bytes()),
+            response("200 OK", &[], layers.as_bytes()),
+            response("200 OK", &[], layers.as_bytes())
+        ]);
+        assert!(resolve_from(&registry, Some("initrd")).await.err().unwrap().contains("expected sha256:"));
+        assert!(resolve_from(&registry, None).await.err().unwrap().contains("has 2 layers (Image, initrd)"));
+        assert!(resolve_from(&registry, Some("dtb")).await.err().unwrap().contains("has no layer dtb"));
+    }
+
+    #[tokio::test]
+    async fn asks_the_token_service_for_a_pull_token()
+    {
+        let tokens = StandIn::start(vec![ response("200 OK", &[], br#"{"token":"pull-token"}"#) ]);
+        let registry = StandIn::start(vec![
+            response("401 Unauthorized", &[("WWW-Authenticate", format!(r#"Bearer realm="http://{}/token",service="registry""#, tokens.addr))], b"")
+        ]);
+
+        /* a local registry is reached over plain HTTP, so the token it asks for mustn't be sent to it */
+        let e = resolve_from(&registry, None).await.err().unwrap();
+        assert!(e.contains("Won't send a bearer token"));
+
+        let requests = tokens.requests();
+        assert!(requests.len() == 1 && requests[0].starts_with("get /token?service=registry&scope=repository%3adiosix%2flinux%3apull "));
+        assert!(requests[0].contains("authorization:") == false);
+        assert!(registry.requests().len() == 1);
+    }
+
+    #[tokio::test]
+    async fn times_out_waiting_for_the_registry()
+    {
+        let registry = StandIn::start(vec![ None ]);
+        assert!(resolve_from(&registry, None).await.err().unwrap().contains("Timed out"));
+
+        /* and for its token service */
+        let tokens = StandIn::start(vec![ None ]);
+        let registry = StandIn::start(vec![
+            response("401 Unauthorized", &[("WWW-Authenticate", format!(r#"Bearer realm="http://{}/token""#, tokens.addr))], b"")
+        ]);
+        assert!(resolve_from(&registry, None).await.err().unwrap().contains("Timed out waiting for a token"));
+    }
+}
@@ -0,0 +1,138 @@
+/* Restrict where guest OS images can be downloaded from
+ *
+ * The manifest can insist on HTTPS, limit downloads to an allowlist of hosts,
+ * and cap the size of any one download. URLs are checked before they are
+ * fetched and whenever a server redirects elsewhere, and sizes are checked
+ * against the server's Content-Length and again as the body arrives.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use reqwest::Url;
+
+use super::Downloads;
+
+#[derive(Clone, Default)]
+pub struct DownloadPolicy
+{
+    https_only: bool,
+    allowed_hosts: Option<Vec<String>>,
+    max_size: Option<u64>
+}
+
+impl DownloadPolicy
+{
+    /* create a policy from the manifest's downloads section, if any. no section means no restrictions */
+    pub fn new(downloads: &Option<Downloads>) -> DownloadPolicy
+    {
+        match downloads
+        {
+            Some(d) => DownloadPolicy
+            {
+                https_only: d.https_only == Some(true),
+                allowed_hosts: d.allowed_hosts.as_ref().map(|hosts| hosts.iter().map(|h| h.to_lowercase()).collect()),
+                max_size: d.max_size
+            },
+            None => DownloadPolicy::default()
+        }
+    }
+
+    /* check a URL may be fetched
+       => url = URL about to be fetched
+       <= returns Ok if allowed, or an error message explaining why not */
+    pub fn check_url(&self, url: &str) -> Result<(), String>
+    {
+        let parsed = match Url::parse(url)
+        {
+            Ok(u) => u,
+            Err(e) => return Err(format!("Can't parse URL {}: {}", url, e))
+        };
+
+        if self.https_only == true && parsed.scheme() != "https"
+        {
+            return Err(format!("Download policy only allows HTTPS, so {} can't be fetched", url));
+        }
+
+        if let Some(allowed) = &self.allowed_hosts
+        {
+            let host = match parsed.host_str()
+            {
+                Some(h) => h.to_lowercase(),
+                None => return Err(format!("Download policy needs a host name in {}", url))
+            };
+
+            let permitted = allowed.iter().any(|pattern| match pattern.strip_prefix("*.")
+            {
+                Some(domain) => host.ends_with(&format!(".{}", domain)),
+                None => *pattern == host
+            });
+
+            if permitted == false
+            {
+                return Err(format!("Download policy doesn't allow host {}, so {} can't be fetched", host, url));
+            }
+        }
+
+        Ok(())
+    }
+
+    /* check a download's size is within the limit
+       => size = size of the download, or the amount received so far
+          url = URL being fetched, for error messages
+       <= returns Ok if within the limit, or an error message */
+    pub fn check_size(&self, size: u64, url: &str) -> Result<(), String>
+    {
+        match self.max_size
+        {
+            Some(max) if size > max => Err(format!("Download policy limits downloads to {} bytes, and {} is larger", max, url)),
+            _ => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /* create a policy from a manifest's downloads section */
+    fn policy(downloads: &str) -> DownloadPolicy
+    {
+        DownloadPolicy::new(&Some(toml::from_str(downloads).unwrap()))
+    }
+
+    #[test]
+    fn allows_listed_hosts_and_domains()
+    {
+        let policy = policy("allowed_hosts = [ \"Mirror.example.org\", \"*.example.com\" ]");
+
+        assert!(policy.check_url("https://mirror.example.org/guest").is_ok());
+        assert!(policy.check_url("http://MIRROR.example.org:8080/guest").is_ok());
+        assert!(policy.check_url("https://downloads.example.com/guest").is_ok());
+        assert!(policy.check_url("https://a.b.example.com/guest").is_ok());
+
+        /* a wildcard covers hosts in the domain, not the domain itself or lookalikes */
+        assert!(policy.check_url("https://example.com/guest").is_err());
+        assert!(policy.check_url("https://badexample.com/guest").is_err());
+        assert!(policy.check_url("https://example.com.evil.org/guest").is_err());
+        assert!(policy.check_url("https://other.example.org/guest").is_err());
+        assert!(policy.check_url("file:///tmp/guest").is_err());
+        assert!(policy.check_url("not a url").is_err());
+    }
+
+    #[test]
+    fn enforces_https_and_size()
+    {
+        let policy = policy("https_only = true\nmax_size = 1000");
+        assert!(policy.check_url("https://anywhere.org/guest").is_ok());
+        assert!(policy.check_url("http://anywhere.org/guest").is_err());
+        assert!(policy.check_size(1000, "https://anywhere.org/guest").is_ok());
+        assert!(policy.check_size(1001, "https://anywhere.org/guest").is_err());
+
+        let unrestricted = DownloadPolicy::new(&None);
+        assert!(unrestricted.check_url("http://anywhere.org/guest").is_ok());
+        assert!(unrestricted.check_size(u64::MAX, "http://anywhere.org/guest").is_ok());
+    }
+}
@@ -0,0 +1,291 @@
+/* Report the progress of guest OS image downloads
+ *
+ * On a terminal, every download in flight gets a line showing how much has
+ * arrived, how fast, and roughly how long is left, redrawn in place as the
+ * downloads go. Otherwise, such as when output is piped into a build log, a
+ * line is logged for each download every so often instead. Messages printed
+ * while downloads are in flight go above the progress lines.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use std::io::{stdout, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/* how often to redraw the progress lines on a terminal */
+static REDRAW_INTERVAL: u64 = 200; /* in milliseconds */
+
+/* how often to log a download's progress when not on a terminal */
+static LOG_INTERVAL: u64 = 10; /* in seconds */
+
+/* a download in flight */
+struct Download
+{
+    label: String,
+    received: u64,
+    total: Option<u64>,
+    resumed_from: u64, /* bytes already present when this attempt started, left out of the rate */
+    started: Instant,
+    last_logged: Instant
+}
+
+struct State
+{
+    downloads: Vec<Download>,
+    lines_drawn: usize,
+    last_drawn: Option<Instant>
+}
+
+#[derive(Clone)]
+pub struct Progress
+{
+    enabled: bool,
+    terminal: bool,
+    state: Arc<Mutex<State>>
+}
+
+impl Progress
+{
+    /* create a progress reporter
+       => enabled = true to report progress, false to stay quiet */
+    pub fn new(enabled: bool) -> Progress
+    {
+        let terminal = atty::is(atty::Stream::Stdout) && std::env::var("TERM").map_or(true, |t| t != "dumb");
+        Progress
+        {
+            enabled,
+            terminal,
+            state: Arc::new(Mutex::new(State { downloads: Vec::new(), lines_drawn: 0, last_drawn: None }))
+        }
+    }
+
+    /* note a download has started, or restarted
+       => label = guest's label
+          offset = number of bytes already downloaded, if resuming
+          total = expected size of the whole file, if known */
+    pub fn begin(&self, label: &String, offset: u64, total: Option<u64>)
+    {
+        if self.enabled == false
+        {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.downloads.retain(|d| d.label != *label);
+        state.downloads.push(Download
+        {
+            label: label.clone(),
+            received: offset,
+            total,
+            resumed_from: offset,
+            started: Instant::now(),
+            last_logged: Instant::now()
+        });
+        self.draw(&mut state, false);
+    }
+
+    /* note more of a download has arrived
+       => label = guest's label
+          bytes = number of bytes just received */
+    pub fn advance(&self, label: &String, bytes: u64)
+    {
+        if self.enabled == false
+        {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let mut log = None;
+        if let Some(download) = state.downloads.iter_mut().find(|d| d.label == *label)
+        {
+            download.received += bytes;
+            if self.terminal == false && now.duration_since(download.last_logged) >= Duration::from_secs(LOG_INTERVAL)
+            {
+                download.last_logged = now;
+                log = Some(describe(download));
+            }
+        }
+
+        match log
+        {
+            Some(line) => println!("Downloading {}", line),
+            None => self.draw(&mut state, false)
+        }
+    }
+
+    /* note a download has finished, successfully or not
+       => label = guest's label */
+    pub fn finish(&self, label: &String)
+    {
+        if self.enabled == false
+        {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.downloads.retain(|d| d.label != *label);
+        self.draw(&mut state, true);
+    }
+
+    /* print a message above any progress lines
+       => msg = message to print */
+    pub fn println(&self, msg: String)
+    {
+        let mut state = self.state.lock().unwrap();
+        self.erase(&mut state);
+        println!("{}", msg);
+        self.draw(&mut state, true);
+    }
+
+    /* redraw the progress lines on a terminal, if it's time to
+       => state = reporter's state
+          now = true to redraw regardless of when they were last drawn */
+    fn draw(&self, state: &mut State, now: bool)
+    {
+        if self.terminal == false || self.enabled == false
+        {
+            return;
+        }
+
+        let due = match state.last_drawn
+        {
+            Some(t) => t.elapsed() >= Duration::from_millis(REDRAW_INTERVAL),
+            None => true
+        };
+        if now == false && due == false
+        {
+            return;
+        }
+
+        self.erase(state);
+        let mut out = stdout();
+        for download in &state.downloads
+        {
+            let _ = writeln!(out, "  {}", describe(download));
+        }
+        let _ = out.flush();
+
+        state.lines_drawn = state.downloads.len();
+        state.last_drawn = Some(Instant::now());
+    }
+
+    /* remove the progress lines from the terminal, if any are drawn */
+    fn erase(&self, state: &mut State)
+    {
+        if state.lines_drawn > 0
+        {
+            let mut out = stdout();
+            let _ = write!(out, "\x1b[{}A\x1b[J", state.lines_drawn);
+            let _ = out.flush();
+            state.lines_drawn = 0;
+        }
+    }
+}
+
+/* describe a download's progress, eg: kern: 12.0 MiB of 40.0 MiB (30%), 3.1 MiB/s, 9s left */
+fn describe(download: &Download) -> String
+{
+    let elapsed = download.started.elapsed().as_secs_f64();
+    let rate = match elapsed > 0.0
+    {
+        true => (download.received - download.resumed_from) as f64 / elapsed,
+        false => 0.0
+    };
+
+    match download.total
+    {
+        Some(total) if total > 0 =>
+        {
+            let left = total.saturating_sub(download.received);
+            let eta = match rate > 0.0
+            {
+                true => humantime::format_duration(Duration::from_secs((left as f64 / rate) as u64)).to_string(),
+                false => String::from("?")
+            };
+
+            format!("{}: {} of {} ({}%), {}/s, {} left", download.label, human_size(download.received),
+                human_size(total), download.received * 100 / total, human_size(rate as u64), eta)
+        },
+        _ => format!("{}: {}, {}/s", download.label, human_size(download.received), human_size(rate as u64))
+    }
+}
+
+/* describe a number of bytes in a human-friendly way, eg: 3.1 MiB */
+fn human_size(bytes: u64) -> String
+{
+    let units = [ "KiB", "MiB", "GiB", "TiB" ];
+    if bytes < 1024
+    {
+        return format!("{} bytes", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < units.len()
+    {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, units[unit])
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    static MIB: u64 = 1024 * 1024;
+
+    /* a download that started some seconds ago */
+    fn download(received: u64, resumed_from: u64, total: Option<u64>, seconds: u64) -> Download
+    {
+        let started = Instant::now().checked_sub(Duration::from_secs(seconds)).unwrap();
+        Download { label: String::from("kern"), received, total, resumed_from, started, last_logged: started }
+    }
+
+    #[test]
+    fn sizes_roll_over_into_larger_units()
+    {
+        assert_eq!(human_size(0), "0 bytes");
+        assert_eq!(human_size(1023), "1023 bytes");
+        assert_eq!(human_size(1024), "1.0 KiB");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(MIB), "1.0 MiB");
+        assert_eq!(human_size(3 * MIB + MIB / 10), "3.1 MiB");
+        assert_eq!(human_size(1024 * MIB), "1.0 GiB");
+        assert_eq!(human_size(1024 * 1024 * MIB), "1.0 TiB");
+
+        /* there's no unit beyond TiB */
+        assert_eq!(human_size(2048 * 1024 * 1024 * MIB), "2048.0 TiB");
+    }
+
+    #[test]
+    fn describes_downloads_with_and_without_a_total()
+    {
+        let line = describe(&download(10 * MIB, 0, Some(40 * MIB), 10));
+        assert!(line.starts_with("kern: 10.0 MiB of 40.0 MiB (25%), "));
+        assert!(line.ends_with("s left"));
+
+        /* the rate leaves out what was there before the download resumed */
+        let line = describe(&download(30 * MIB, 20 * MIB, Some(40 * MIB), 100));
+        assert!(line.starts_with("kern: 30.0 MiB of 40.0 MiB (75%), 102.4 KiB/s, "));
+
+        /* without a total, or with one of zero, there's no percentage or time left */
+        let line = describe(&download(10 * MIB, 0, None, 10));
+        assert!(line.starts_with("kern: 10.0 MiB, ") && line.ends_with("/s"));
+        let line = describe(&download(0, 0, Some(0), 10));
+        assert_eq!(line, "kern: 0 bytes, 0 bytes/s");
+    }
+
+    #[test]
+    fn says_time_left_is_unknown_until_data_arrives()
+    {
+        assert_eq!(describe(&download(5 * MIB, 5 * MIB, Some(10 * MIB), 10)), "kern: 5.0 MiB of 10.0 MiB (50%), 0 bytes/s, ? left");
+        assert_eq!(describe(&download(0, 0, Some(10 * MIB), 0)), "kern: 0 bytes of 10.0 MiB (0%), 0 bytes/s, ? left");
+    }
+}
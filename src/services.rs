@@ -0,0 +1,753 @@
+/* Build system services from source with cargo, and find their binaries
+ *
+ * With --build-services, each included service is built by running cargo in its
+ * source directory, for the target architecture and the build quality selected,
+ * before it's added to the image. cargo's messages are read as JSON so that its
+ * diagnostics can be captured, and shown if the build fails or the user asked for
+ * progress, and so that the binary it produced can be picked out of its list of
+ * artifacts rather than guessed at.
+ *
+ * Otherwise, the service's binary is looked for where cargo would have put it.
+ * cargo metadata is asked for the service's target directory, which takes
+ * CARGO_TARGET_DIR and shared workspace target directories into account, and
+ * for the name of the service crate's binary. Either can be given in the
+ * service's configuration instead.
+ *
+ * A service crate can describe itself in its Cargo.toml, so the service's author
+ * owns its description and the permissions it needs:
+ *
+ * package.description                = description of what this service does
+ * package.metadata.diosix.properties = array of permissions and other properties granted to this service
+ * package.metadata.diosix.ram        = number of megabytes of RAM to allocate for this service
+ * package.metadata.diosix.cpus       = number of virtual CPU cores to allocate for this service
+ *
+ * Any other key in package.metadata.diosix is an error, so a misspelt setting isn't
+ * silently ignored. Anything also set in the service's table in the manifest
+ * configuration file is overridden by the manifest, and reported if the two disagree.
+ *
+ * Services can also be discovered by scanning a directory: every subdirectory
+ * containing a Cargo.toml is a service, named after the subdirectory, unless the
+ * name is left out by the scan's include and exclude patterns. These are globs in
+ * which * matches any run of characters and ? matches any one character.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::fs::{canonicalize, read_dir, read_to_string};
+
+use serde_derive::Deserialize;
+use regex::Regex;
+
+/* one line of cargo's JSON output. only the fields we need are decoded */
+#[derive(Deserialize)]
+struct Message
+{
+    reason: String,
+    target: Option<ArtifactTarget>,
+    executable: Option<String>,
+    message: Option<Diagnostic>
+}
+
+#[derive(Deserialize)]
+struct ArtifactTarget
+{
+    name: String
+}
+
+#[derive(Deserialize)]
+struct Diagnostic
+{
+    rendered: Option<String>
+}
+
+/* the parts of cargo metadata's output we need */
+#[derive(Deserialize)]
+struct Metadata
+{
+    packages: Vec<Package>,
+    target_directory: String
+}
+
+#[derive(Deserialize)]
+struct Package
+{
+    manifest_path: String,
+    targets: Vec<PackageTarget>
+}
+
+#[derive(Deserialize)]
+struct PackageTarget
+{
+    name: String,
+    kind: Vec<String>
+}
+
+/* a service's settings from its crate's Cargo.toml. unknown keys are an error, so that a misspelt
+   setting, such as a service's properties, isn't silently dropped */
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct CrateSettings
+{
+    #[serde(skip)]
+    pub description: Option<String>,
+    pub properties: Option<Vec<String>>,
+    pub ram: Option<usize>,
+    pub cpus: Option<usize>
+}
+
+/* how a service is, or was, built by cargo */
+pub struct CargoOptions
+{
+    pub target_dir: Option<PathBuf>,  /* absolute cargo target directory, or None for cargo's choice */
+    pub target_arch: Option<String>,  /* target triple, or None for cargo's default */
+    pub quality: Option<String>,      /* 'debug', 'release', or the name of a custom cargo profile. debug if None */
+    pub offline: bool,                /* true to stop cargo using the network to fetch crates */
+    pub verbose: bool                 /* true to show cargo's diagnostics even if the build succeeds */
+}
+
+/* build a service with cargo and find the binary it produced
+   => dir = service's source code directory
+      name = service's name
+      binary = name of the service's binary, if it isn't named after the service
+      options = how to run cargo
+   <= returns pathname of the built binary, or an error message */
+pub fn build(dir: &PathBuf, name: &String, binary: &Option<String>, options: &CargoOptions) -> Result<PathBuf, String>
+{
+    let mut cmd = Command::new("cargo");
+    cmd.current_dir(dir).arg("build").arg("--message-format=json");
+
+    if options.offline == true
+    {
+        cmd.arg("--offline");
+    }
+
+    if let Some(td) = &options.target_dir
+    {
+        cmd.arg("--target-dir").arg(td);
+    }
+
+    if let Some(ta) = &options.target_arch
+    {
+        cmd.arg("--target").arg(ta);
+    }
+
+    match options.quality.as_deref()
+    {
+        None | Some("debug") => (),
+        Some("release") => { cmd.arg("--release"); },
+        Some(profile) => { cmd.arg("--profile").arg(profile); }
+    }
+
+    if options.verbose == true
+    {
+        println!("Building system service {} using cargo in {}...", name, dir.display());
+    }
+
+    let output = match cmd.output()
+    {
+        Ok(o) => o,
+        Err(e) => return Err(format!("Can't run cargo to build service {}: {}", name, e))
+    };
+
+    /* gather up the compiler's diagnostics and the executables built */
+    let mut diagnostics = String::new();
+    let mut executables = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines()
+    {
+        let message: Message = match serde_json::from_str(line)
+        {
+            Ok(m) => m,
+            Err(_) => continue /* skip anything that isn't one of cargo's messages, such as build script output */
+        };
+
+        match message.reason.as_str()
+        {
+            "compiler-message" => if let Some(rendered) = message.message.and_then(|m| m.rendered)
+            {
+                diagnostics.push_str(rendered.as_str());
+            },
+            "compiler-artifact" => if let (Some(target), Some(exe)) = (message.target, message.executable)
+            {
+                executables.push((target.name, PathBuf::from(exe)));
+            },
+            _ => ()
+        }
+    }
+
+    /* cargo's own errors, such as a broken Cargo.toml, go to stderr */
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.success() == false
+    {
+        eprint!("{}{}", diagnostics, stderr);
+        return Err(format!("cargo failed to build service {} ({})", name, output.status));
+    }
+
+    if options.verbose == true
+    {
+        print!("{}", diagnostics);
+    }
+
+    /* prefer the binary we were told to use, or the one named after the service, or else the only binary built */
+    let wanted = binary.as_ref().unwrap_or(name);
+    if let Some((_, exe)) = executables.iter().find(|(target, _)| target == wanted)
+    {
+        return Ok(exe.clone());
+    }
+
+    match (executables.len(), binary)
+    {
+        (1, None) => Ok(executables[0].1.clone()),
+        (0, _) => Err(format!("cargo didn't build a binary for service {} in {}", name, dir.display())),
+        (_, _) => Err(format!("cargo didn't build a binary called {} for service {}. It built: {}", wanted, name,
+                    executables.iter().map(|(target, _)| target.as_str()).collect::<Vec<&str>>().join(", ")))
+    }
+}
+
+/* find a service's already-built binary
+   => dir = service's source code directory
+      name = service's name
+      binary = name of the service's binary, or None to ask cargo metadata, falling back to the service's name
+      options = how the service was built. if it has no target directory, cargo metadata is asked for it,
+                falling back to <dir>/target
+   <= returns pathname of the binary, or an error message listing every pathname tried */
+pub fn locate(dir: &PathBuf, name: &String, binary: &Option<String>, options: &CargoOptions) -> Result<PathBuf, String>
+{
+    let target_dir = &options.target_dir;
+
+    /* only ask cargo if there's something we don't know */
+    let mut notes = Vec::new();
+    let metadata = match (binary, target_dir)
+    {
+        (Some(_), Some(_)) => None,
+        (_, _) => match metadata(dir)
+        {
+            Ok(m) => Some(m),
+            Err(e) =>
+            {
+                notes.push(e);
+                None
+            }
+        }
+    };
+
+    let target_dir = match (target_dir, &metadata)
+    {
+        (Some(td), _) => td.clone(),
+        (None, Some(m)) => PathBuf::from(&m.target_directory),
+        (None, None) =>
+        {
+            let mut td = dir.clone();
+            td.push("target");
+            td
+        }
+    };
+
+    let binary = match (binary, &metadata)
+    {
+        (Some(b), _) => b.clone(),
+        (None, Some(m)) => match package_binary(m, dir, name)
+        {
+            Some(b) => b,
+            None => name.clone()
+        },
+        (None, None) => name.clone()
+    };
+
+    let profile = match &options.quality
+    {
+        Some(q) => q.as_str(),
+        None => "debug"
+    };
+
+    /* cross-compiled binaries are in a directory named after the target. a binary built for the host,
+    such as when self-hosting, isn't */
+    let mut candidates = Vec::new();
+    if let Some(ta) = &options.target_arch
+    {
+        candidates.push([target_dir.clone(), PathBuf::from(ta), PathBuf::from(profile), PathBuf::from(&binary)].iter().collect::<PathBuf>());
+    }
+    candidates.push([target_dir.clone(), PathBuf::from(profile), PathBuf::from(&binary)].iter().collect::<PathBuf>());
+
+    if let Some(found) = candidates.iter().find(|c| c.is_file() == true)
+    {
+        return Ok(found.clone());
+    }
+
+    let mut msg = format!("Can't find binary {} for service {}. Tried:", binary, name);
+    for candidate in &candidates
+    {
+        msg.push_str(format!("\n  {}", candidate.display()).as_str());
+    }
+    for note in &notes
+    {
+        msg.push_str(format!("\n{}", note).as_str());
+    }
+    Err(msg)
+}
+
+/* ask cargo metadata about the crate in a directory
+   => dir = crate's source code directory
+   <= returns cargo's description of the crate and its workspace, or an error message */
+fn metadata(dir: &PathBuf) -> Result<Metadata, String>
+{
+    let output = match Command::new("cargo").current_dir(dir).args(["metadata", "--format-version", "1", "--no-deps"]).output()
+    {
+        Ok(o) => o,
+        Err(e) => return Err(format!("Can't run cargo metadata in {}: {}", dir.display(), e))
+    };
+
+    if output.status.success() == false
+    {
+        return Err(format!("cargo metadata failed in {} ({}): {}", dir.display(), output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()));
+    }
+
+    match serde_json::from_slice(&output.stdout)
+    {
+        Ok(m) => Ok(m),
+        Err(e) => Err(format!("Can't parse cargo metadata's output in {}: {}", dir.display(), e))
+    }
+}
+
+/* pick the binary out of the crate in a directory
+   => metadata = cargo's description of the crate's workspace
+      dir = crate's source code directory
+      name = service's name
+   <= returns the binary named after the service, or else the crate's only binary, or None if there's no clear choice */
+fn package_binary(metadata: &Metadata, dir: &PathBuf, name: &String) -> Option<String>
+{
+    let mut manifest = canonicalize(dir).ok()?;
+    manifest.push("Cargo.toml");
+
+    let package = metadata.packages.iter().find(|p| canonicalize(&p.manifest_path).ok().as_ref() == Some(&manifest))?;
+    let binaries: Vec<&String> = package.targets.iter()
+        .filter(|t| t.kind.iter().any(|k| k == "bin"))
+        .map(|t| &t.name)
+        .collect();
+
+    match binaries.iter().find(|b| **b == name)
+    {
+        Some(b) => Some((*b).clone()),
+        None => match binaries.len()
+        {
+            1 => Some(binaries[0].clone()),
+            _ => None
+        }
+    }
+}
+
+/* read a service's settings from the Cargo.toml in its source code directory
+   => dir = service's source code directory
+   <= returns the settings found, which are all None if there's no Cargo.toml, or an error message */
+pub fn crate_settings(dir: &Path) -> Result<CrateSettings, String>
+{
+    let path = dir.join("Cargo.toml");
+    if path.exists() == false
+    {
+        return Ok(CrateSettings::default());
+    }
+
+    let contents = match read_to_string(&path)
+    {
+        Ok(c) => c,
+        Err(e) => return Err(format!("Can't read {}: {}", path.display(), e))
+    };
+
+    let cargo_toml: toml::Value = match toml::from_str(contents.as_str())
+    {
+        Ok(v) => v,
+        Err(e) => return Err(format!("Can't parse {}: {}", path.display(), e))
+    };
+
+    /* the package's description may be inherited from its workspace, in which case it's not a string here. leave it be */
+    let package = cargo_toml.get("package");
+    let mut settings: CrateSettings = match package.and_then(|p| p.get("metadata")).and_then(|m| m.get("diosix"))
+    {
+        Some(diosix) => match diosix.clone().try_into()
+        {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Can't parse package.metadata.diosix in {}: {}", path.display(), e))
+        },
+        None => CrateSettings::default()
+    };
+    settings.description = package.and_then(|p| p.get("description")).and_then(|d| d.as_str()).map(|d| d.to_string());
+
+    Ok(settings)
+}
+
+/* pick a service setting from the manifest configuration file or else the service's Cargo.toml
+   => name = service's name
+      setting = name of the setting
+      manifest = setting's value in the manifest configuration file, if any
+      from_crate = setting's value in the service's Cargo.toml, if any
+      conflicts = list to add a message to if the two are set and disagree
+   <= returns the manifest's value if set, or else the Cargo.toml's */
+pub fn merge<T: PartialEq + Debug>(name: &String, setting: &str, manifest: Option<T>, from_crate: Option<T>, conflicts: &mut Vec<String>) -> Option<T>
+{
+    match (manifest, from_crate)
+    {
+        (Some(m), Some(c)) =>
+        {
+            if m != c
+            {
+                conflicts.push(format!("Service {}'s {} setting in the manifest ({:?}) overrides its Cargo.toml's ({:?})", name, setting, m, c));
+            }
+            Some(m)
+        },
+        (m, c) => m.or(c)
+    }
+}
+
+/* find the service crates in a directory
+   => dir = directory to scan
+      include = glob patterns of the names of services to include, or None to include all of them
+      exclude = glob patterns of the names of services to leave out, if any
+   <= returns the names of the services found, in alphabetical order, or an error message */
+pub fn scan(dir: &PathBuf, include: &Option<Vec<String>>, exclude: &Option<Vec<String>>) -> Result<Vec<String>, String>
+{
+    let include = match include
+    {
+        Some(patterns) => Some(compile_globs(patterns)?),
+        None => None
+    };
+    let exclude = match exclude
+    {
+        Some(patterns) => compile_globs(patterns)?,
+        None => Vec::new()
+    };
+
+    let entries = match read_dir(dir)
+    {
+        Ok(e) => e,
+        Err(e) => return Err(format!("Can't scan {} for services: {}", dir.display(), e))
+    };
+
+    let mut names = Vec::new();
+    for entry in entries.flatten()
+    {
+        let mut manifest = entry.path();
+        manifest.push("Cargo.toml");
+        if manifest.is_file() == false
+        {
+            continue;
+        }
+
+        let name = entry.file_name().to_string_lossy().to_string();
+        let included = match &include
+        {
+            Some(patterns) => patterns.iter().any(|p| p.is_match(&name)),
+            None => true
+        };
+
+        if included == true && exclude.iter().any(|p| p.is_match(&name)) == false
+        {
+            names.push(name);
+        }
+    }
+
+    names.sort();
+    Ok(names)
+}
+
+/* turn glob patterns into regular expressions that match whole names
+   => patterns = globs in which * matches any run of characters and ? any one character
+   <= returns the compiled patterns, or an error message */
+fn compile_globs(patterns: &[String]) -> Result<Vec<Regex>, String>
+{
+    patterns.iter().map(|pattern|
+    {
+        let re = format!("^{}$", regex::escape(pattern).replace(r"\*", ".*").replace(r"\?", "."));
+        Regex::new(re.as_str()).map_err(|e| format!("Bad service name pattern {}: {}", pattern, e))
+    }).collect()
+}
+
+/* suggest which of the defined services a misspelled name was meant to be
+   => name = name that isn't defined
+      defined = names of the services that are defined
+   <= returns the closest defined name, or None if none is close enough to be a likely typo */
+pub fn suggest<'a>(name: &str, defined: impl Iterator<Item = &'a String>) -> Option<String>
+{
+    /* allow roughly one mistake for every three characters */
+    let limit = std::cmp::max(1, name.chars().count() / 3);
+
+    let mut best: Option<(usize, &String)> = None;
+    for candidate in defined
+    {
+        let distance = edit_distance(name, candidate);
+        let closer = match best
+        {
+            Some((d, b)) => distance < d || (distance == d && candidate < b),
+            None => true
+        };
+
+        if distance <= limit && closer == true
+        {
+            best = Some((distance, candidate));
+        }
+    }
+
+    best.map(|(_, b)| b.clone())
+}
+
+/* count the single-character insertions, deletions, and substitutions needed to turn one string into another */
+fn edit_distance(a: &str, b: &str) -> usize
+{
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate()
+    {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate()
+        {
+            let cost = match ca == *cb
+            {
+                true => 0,
+                false => 1
+            };
+            current.push(*[previous[j] + cost, previous[j + 1] + 1, current[j] + 1].iter().min().unwrap());
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    use crate::testutil::scratch;
+
+    fn strings(items: &[&str]) -> Vec<String>
+    {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn scans_for_crates_by_glob()
+    {
+        let dir = scratch("services-scan");
+        for name in &["net", "netdbg", "gpu", "a.b", "axb"]
+        {
+            create_dir_all(dir.join(name)).unwrap();
+            write(dir.join(name).join("Cargo.toml"), "[package]\n").unwrap();
+        }
+        create_dir_all(dir.join("docs")).unwrap();
+        write(dir.join("Cargo.toml"), "[workspace]\n").unwrap();
+
+        assert_eq!(scan(&dir, &None, &None).unwrap(), strings(&["a.b", "axb", "gpu", "net", "netdbg"]));
+        assert_eq!(scan(&dir, &Some(strings(&["net*", "g?u"])), &Some(strings(&["*dbg"]))).unwrap(), strings(&["gpu", "net"]));
+        assert_eq!(scan(&dir, &Some(strings(&["a.b"])), &None).unwrap(), strings(&["a.b"]));
+        assert_eq!(scan(&dir, &None, &Some(strings(&["*"]))).unwrap(), strings(&[]));
+
+        let _ = remove_dir_all(&dir);
+        assert!(scan(&dir, &None, &None).is_err());
+    }
+
+    #[test]
+    fn globs_match_whole_names()
+    {
+        let globs = compile_globs(&strings(&["net", "dev-?", "*-svc", "[x]+"])).unwrap();
+        let matches = |name: &str| globs.iter().position(|g| g.is_match(name));
+
+        assert_eq!(matches("net"), Some(0));
+        assert_eq!(matches("network"), None);
+        assert_eq!(matches("dev-1"), Some(1));
+        assert_eq!(matches("dev-12"), None);
+        assert_eq!(matches("log-svc"), Some(2));
+        assert_eq!(matches("-svc"), Some(2));
+        assert_eq!(matches("log-svc2"), None);
+        assert_eq!(matches("[x]+"), Some(3));
+        assert_eq!(matches("xx"), None);
+    }
+
+    #[test]
+    fn measures_edit_distance()
+    {
+        let distance = |a: &str, b: &str| edit_distance(a, b);
+        assert_eq!(distance("", ""), 0);
+        assert_eq!(distance("net", "net"), 0);
+        assert_eq!(distance("", "net"), 3);
+        assert_eq!(distance("net", ""), 3);
+        assert_eq!(distance("net", "nat"), 1);
+        assert_eq!(distance("net", "nett"), 1);
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(distance("café", "cafe"), 1);
+    }
+
+    #[test]
+    fn suggests_close_names_only()
+    {
+        let defined = strings(&["console", "network", "gpu", "netlog"]);
+        let suggest_for = |name: &str| suggest(name, defined.iter());
+
+        assert_eq!(suggest_for("consle"), Some(String::from("console")));
+        assert_eq!(suggest_for("netwrok"), Some(String::from("network")));
+        assert_eq!(suggest_for("gpv"), Some(String::from("gpu")));
+        assert_eq!(suggest_for("storage"), None);
+        assert_eq!(suggest_for("xyz"), None);
+
+        /* ties go to the first name alphabetically, whatever order they're defined in */
+        let defined = strings(&["netb", "neta"]);
+        assert_eq!(suggest(&String::from("net"), defined.iter()), Some(String::from("neta")));
+        assert_eq!(suggest(&String::from("net"), Vec::new().iter()), None);
+    }
+
+    /* how a service was built, for locating it */
+    fn options(target_dir: Option<PathBuf>, target_arch: Option<&str>, quality: Option<&str>) -> CargoOptions
+    {
+        CargoOptions
+        {
+            target_dir,
+            target_arch: target_arch.map(String::from),
+            quality: quality.map(String::from),
+            offline: false,
+            verbose: false
+        }
+    }
+
+    /* a fake build artifact in a target directory */
+    fn artifact(parts: &[&str]) -> PathBuf
+    {
+        let path: PathBuf = parts.iter().collect();
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(&path, "binary").unwrap();
+        path
+    }
+
+    #[test]
+    fn locates_cross_compiled_binaries_before_host_ones()
+    {
+        let dir = scratch("services-locate-order");
+        let td = dir.join("out").to_string_lossy().to_string();
+        let find = |quality: Option<&str>| locate(&dir, &String::from("net"), &Some(String::from("netsvc")),
+                                                  &options(Some(PathBuf::from(&td)), Some("riscv64gc-unknown-none-elf"), quality));
+
+        /* a binary built for the host is only used if there isn't one built for the target */
+        let host = artifact(&[td.as_str(), "release", "netsvc"]);
+        assert_eq!(find(Some("release")).unwrap(), host);
+        let cross = artifact(&[td.as_str(), "riscv64gc-unknown-none-elf", "release", "netsvc"]);
+        assert_eq!(find(Some("release")).unwrap(), cross);
+
+        /* the profile defaults to debug, and a custom profile has its own directory */
+        let debug = artifact(&[td.as_str(), "debug", "netsvc"]);
+        assert_eq!(find(None).unwrap(), debug);
+        let custom = artifact(&[td.as_str(), "riscv64gc-unknown-none-elf", "small", "netsvc"]);
+        assert_eq!(find(Some("small")).unwrap(), custom);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn lists_every_pathname_tried()
+    {
+        let dir = scratch("services-locate-missing");
+        let target = dir.join("target");
+
+        /* without a Cargo.toml, cargo metadata fails, so the target directory and binary fall back to defaults */
+        let e = locate(&dir, &String::from("net"), &None, &options(None, Some("riscv64gc-unknown-none-elf"), Some("release"))).unwrap_err();
+        let tried: Vec<&str> = e.lines().collect();
+        assert_eq!(tried[0], "Can't find binary net for service net. Tried:");
+        assert_eq!(tried[1], format!("  {}", target.join("riscv64gc-unknown-none-elf").join("release").join("net").display()));
+        assert_eq!(tried[2], format!("  {}", target.join("release").join("net").display()));
+        assert!(tried[3].contains("cargo metadata"));
+
+        /* and with no target architecture, only the host's directory is tried */
+        let e = locate(&dir, &String::from("net"), &Some(String::from("netsvc")), &options(None, None, None)).unwrap_err();
+        assert_eq!(e.lines().nth(1).unwrap(), format!("  {}", target.join("debug").join("netsvc").display()));
+
+        let found = artifact(&[target.to_string_lossy().as_ref(), "debug", "netsvc"]);
+        assert_eq!(locate(&dir, &String::from("net"), &Some(String::from("netsvc")), &options(None, None, None)).unwrap(), found);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn picks_the_binary_named_after_the_service_or_the_only_one()
+    {
+        let dir = scratch("services-package-binary");
+        write(dir.join("Cargo.toml"), "[package]\n").unwrap();
+        let manifest = dir.join("Cargo.toml").to_string_lossy().replace('\\', "\\\\");
+
+        let metadata = |targets: &str| -> Metadata
+        {
+            serde_json::from_str(format!(concat!(
+                r#"{{"target_directory":"/t","packages":["#,
+                r#"{{"manifest_path":"/elsewhere/Cargo.toml","targets":[{{"name":"net","kind":["bin"]}}]}},"#,
+                r#"{{"manifest_path":"{}","targets":[{}]}}]}}"#), manifest, targets).as_str()).unwrap()
+        };
+        let net = String::from("net");
+
+        let m = metadata(r#"{"name":"netlib","kind":["lib"]},{"name":"netd","kind":["bin"]}"#);
+        assert_eq!(package_binary(&m, &dir, &net), Some(String::from("netd")));
+        let m = metadata(r#"{"name":"netd","kind":["bin"]},{"name":"net","kind":["bin"]}"#);
+        assert_eq!(package_binary(&m, &dir, &net), Some(String::from("net")));
+        let m = metadata(r#"{"name":"netd","kind":["bin"]},{"name":"nettool","kind":["bin"]}"#);
+        assert_eq!(package_binary(&m, &dir, &net), None);
+        let m = metadata(r#"{"name":"netlib","kind":["lib"]}"#);
+        assert_eq!(package_binary(&m, &dir, &net), None);
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reads_settings_from_cargo_toml()
+    {
+        let dir = scratch("services-crate-settings");
+
+        /* a crate without a Cargo.toml has no settings */
+        let settings = crate_settings(&dir).unwrap();
+        assert!(settings.description.is_none() && settings.properties.is_none() && settings.ram.is_none() && settings.cpus.is_none());
+
+        write(dir.join("Cargo.toml"), concat!(
+            "[package]\nname = \"net\"\ndescription = \"network service\"\n",
+            "[package.metadata.diosix]\nproperties = [\"console_write\"]\nram = 16\ncpus = 2\n")).unwrap();
+        let settings = crate_settings(&dir).unwrap();
+        assert_eq!(settings.description, Some(String::from("network service")));
+        assert_eq!(settings.properties, Some(strings(&["console_write"])));
+        assert_eq!((settings.ram, settings.cpus), (Some(16), Some(2)));
+
+        /* a description inherited from the workspace is left for the manifest to give */
+        write(dir.join("Cargo.toml"), "[package]\nname = \"net\"\ndescription.workspace = true\n").unwrap();
+        let settings = crate_settings(&dir).unwrap();
+        assert!(settings.description.is_none() && settings.properties.is_none());
+
+        /* misspelt and mistyped settings are errors rather than ignored */
+        write(dir.join("Cargo.toml"), "[package]\nname = \"net\"\n[package.metadata.diosix]\npropertes = [\"console_write\"]\n").unwrap();
+        assert!(crate_settings(&dir).err().unwrap().contains("propertes"));
+        write(dir.join("Cargo.toml"), "[package]\nname = \"net\"\n[package.metadata.diosix]\nram = \"lots\"\n").unwrap();
+        assert!(crate_settings(&dir).is_err());
+        write(dir.join("Cargo.toml"), "[package\n").unwrap();
+        assert!(crate_settings(&dir).is_err());
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn prefers_the_manifest_and_reports_conflicts()
+    {
+        let name = String::from("net");
+        let mut conflicts = Vec::new();
+
+        assert_eq!(merge(&name, "ram", Some(16), None, &mut conflicts), Some(16));
+        assert_eq!(merge(&name, "ram", None, Some(32), &mut conflicts), Some(32));
+        assert_eq!(merge(&name, "ram", Some(16), Some(16), &mut conflicts), Some(16));
+        assert_eq!(merge::<usize>(&name, "ram", None, None, &mut conflicts), None);
+        assert!(conflicts.is_empty());
+
+        assert_eq!(merge(&name, "cpus", Some(1), Some(2), &mut conflicts), Some(1));
+        assert_eq!(merge(&name, "properties", Some(strings(&["console_read"])), Some(strings(&["console_write"])), &mut conflicts),
+                   Some(strings(&["console_read"])));
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(conflicts[0], "Service net's cpus setting in the manifest (1) overrides its Cargo.toml's (2)");
+        assert!(conflicts[1].contains("properties") && conflicts[1].contains("console_write"));
+    }
+}
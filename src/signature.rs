@@ -0,0 +1,117 @@
+/* Check detached signatures of guest OS images
+ *
+ * Guests can be signed with minisign. The manifest lists the public keys it
+ * trusts, by name, and each signed guest names the key it was signed with and
+ * where to find its detached signature. The signature covers the file as it
+ * was published, before any decompression or extraction, and is checked a
+ * chunk at a time, so only signatures in minisign's default prehashed form
+ * are accepted.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use std::io::prelude::*;
+use std::path::Path;
+use std::fs::File;
+use std::collections::HashMap;
+
+use minisign_verify::{PublicKey, Signature};
+
+use super::digest::CHUNK_SIZE;
+
+/* check a downloaded file against its detached minisign signature
+   => path = pathname of the file as downloaded
+      signature = contents of the minisign signature file
+      key = name of the trusted public key that signed the file
+      keys = table of trusted public keys, in minisign's base64 format, keyed by name
+      label = guest's label, for error messages
+   <= returns Ok if the signature is good, or an error message */
+pub fn verify(path: &Path, signature: &str, key: &String, keys: &HashMap<String, String>, label: &String) -> Result<(), String>
+{
+    let public_key = match keys.get(key)
+    {
+        Some(k) => match PublicKey::from_base64(k.trim())
+        {
+            Ok(pk) => pk,
+            Err(e) => return Err(format!("Can't decode trusted key {} for guest {}: {}", key, label, e))
+        },
+        None => return Err(format!("Guest {} is signed with key {}, which isn't in the manifest's trusted keys", label, key))
+    };
+
+    let signature = match Signature::decode(signature)
+    {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Can't decode signature for guest {}: {}", label, e))
+    };
+
+    let mut verifier = match public_key.verify_stream(&signature)
+    {
+        Ok(v) => v,
+        Err(e) => return Err(format!("Bad signature for guest {} using key {}: {}", label, key, e))
+    };
+
+    let mut fh = match File::open(path)
+    {
+        Ok(fh) => fh,
+        Err(e) => return Err(format!("Can't open {} to check guest {}'s signature: {}", path.display(), label, e))
+    };
+
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop
+    {
+        match fh.read(&mut buffer)
+        {
+            Ok(0) => break,
+            Ok(n) => verifier.update(&buffer[..n]),
+            Err(e) => return Err(format!("Can't read {} to check guest {}'s signature: {}", path.display(), label, e))
+        }
+    }
+
+    match verifier.finalize()
+    {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("Bad signature for guest {} using key {}: {}", label, key, e))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::fs::{remove_dir_all, write};
+
+    use crate::testutil::{scratch, SIGNED_GUEST, TEST_KEY, OTHER_KEY, SIGNATURE};
+
+    fn keys(name: &str, key: &str) -> HashMap<String, String>
+    {
+        let mut keys = HashMap::new();
+        keys.insert(name.to_string(), key.to_string());
+        keys
+    }
+
+    #[test]
+    fn accepts_only_good_signatures_from_trusted_keys()
+    {
+        let dir = scratch("signature-verify");
+        let (path, label, test) = (dir.join("guest"), String::from("g"), String::from("test"));
+        let signature = String::from(SIGNATURE);
+        write(&path, SIGNED_GUEST).unwrap();
+
+        assert!(verify(&path, &signature, &test, &keys("test", TEST_KEY), &label).is_ok());
+
+        /* the key has to be trusted, by the name the guest gives */
+        let e = verify(&path, &signature, &String::from("missing"), &keys("test", TEST_KEY), &label).err().unwrap();
+        assert!(e.contains("isn't in the manifest's trusted keys"));
+        assert!(verify(&path, &signature, &test, &keys("test", OTHER_KEY), &label).err().unwrap().contains("Bad signature"));
+        assert!(verify(&path, &signature, &test, &keys("test", "not a key"), &label).err().unwrap().contains("Can't decode trusted key"));
+        assert!(verify(&path, &String::from("not a signature"), &test, &keys("test", TEST_KEY), &label).err().unwrap().contains("Can't decode signature"));
+
+        /* and the file has to be the one that was signed */
+        write(&path, b"a tampered guest kernel image").unwrap();
+        assert!(verify(&path, &signature, &test, &keys("test", TEST_KEY), &label).err().unwrap().contains("Bad signature"));
+
+        let _ = remove_dir_all(&dir);
+    }
+}
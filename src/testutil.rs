@@ -0,0 +1,109 @@
+/* Helpers shared by the unit tests
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use std::env;
+use std::process;
+use std::path::PathBuf;
+use std::fs::{create_dir_all, remove_dir_all};
+use std::io::prelude::*;
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/* create an empty scratch directory named for the test, clearing out any left by an earlier run.
+   tests remove their scratch directories when they're done with them
+   => name = name of the test, unique among all the tests
+   <= returns pathname of the scratch directory */
+pub fn scratch(name: &str) -> PathBuf
+{
+    let dir = env::temp_dir().join(format!("mkdmfs-test-{}-{}", process::id(), name));
+    let _ = remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    dir
+}
+
+/* a stand-in HTTP server that answers each connection in turn with the next canned response,
+   or never answers it if the response is None, and records the requests it was sent */
+pub struct StandIn
+{
+    pub url: String,
+    pub addr: String,
+    requests: Arc<Mutex<Vec<String>>>
+}
+
+impl StandIn
+{
+    pub fn start(responses: Vec<Option<Vec<u8>>>) -> StandIn
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let url = format!("http://{}/guest", addr);
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let log = requests.clone();
+        thread::spawn(move ||
+        {
+            for response in responses
+            {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                loop
+                {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n"
+                    {
+                        break;
+                    }
+                    request.push_str(line.to_lowercase().as_str());
+                }
+                log.lock().unwrap().push(request);
+
+                match response
+                {
+                    Some(r) => { let _ = stream.write_all(&r); },
+                    None => thread::sleep(Duration::from_secs(5))
+                }
+            }
+        });
+
+        StandIn { url, addr, requests }
+    }
+
+    pub fn requests(&self) -> Vec<String>
+    {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/* build a canned HTTP response */
+pub fn response(status: &str, headers: &[(&str, String)], body: &[u8]) -> Option<Vec<u8>>
+{
+    let mut r = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n", status, body.len());
+    for (name, value) in headers
+    {
+        r.push_str(format!("{}: {}\r\n", name, value).as_str());
+    }
+    r.push_str("\r\n");
+
+    let mut r = r.into_bytes();
+    r.extend_from_slice(body);
+    Some(r)
+}
+
+/* a guest signed by the test key with minisign's default prehashed signature. the secret half of the
+   test key was thrown away once the signature was made. the other key is trusted by no one */
+pub static SIGNED_GUEST: &[u8] = b"a signed guest kernel image";
+pub static TEST_KEY: &str = "RWQVlMlRx3/VUWvkUbzkrWTbZczgTOHPsKQonLRNLylVq5c8WixoBPHA";
+pub static OTHER_KEY: &str = "RWQZnF0yetLwDok1hjAuxPNvufQ8qSG9uTBgneHPXI04Ierk271o49wk";
+pub static SIGNATURE: &str = concat!(
+    "untrusted comment: signature from mkdmfs test key\n",
+    "RUQVlMlRx3/VURASdihbCPJ/lX4AFwJShx8oLJ4a+QQz1bbuarzE0oEp+TipCMW0fzF3kUDjb0eRoJuNiDTdtWErMEDmOM8DaAE=\n",
+    "trusted comment: mkdmfs test fixture\n",
+    "vxyJsjRLZsrgIZgclLrGIsZqUO4436lIQ3x6FRGbVt8rYT+TOqQZD+2xGi7gKBmxc61v302R4yO5BdJEqVT6Bg==\n");
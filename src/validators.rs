@@ -0,0 +1,124 @@
+/* Remember how to ask a server whether a guest OS image has changed
+ *
+ * When a guest is downloaded, the server's ETag and Last-Modified headers, if
+ * any, are kept in a hidden .<label>.validators file next to the guest, along
+ * with the URL they came from. --refresh uses them to make a conditional
+ * request for each guest already present, so only guests whose remote copy has
+ * changed are downloaded again. A guest written by any other means loses its
+ * validators, as they no longer describe it.
+ *
+ * A download in progress keeps a record like this too, saying where it's being
+ * downloaded from, so it's only ever resumed from the same URL, and only with an
+ * If-Range request that starts over if the remote file has changed since.
+ *
+ * (c) Chris Williams, 2020.
+ *
+ * See LICENSE for usage and copying.
+ */
+
+use std::path::{Path, PathBuf};
+use std::fs::{read_to_string, remove_file};
+
+use serde_derive::{Deserialize, Serialize};
+use reqwest::RequestBuilder;
+use reqwest::header::{HeaderMap, HeaderName, ETAG, LAST_MODIFIED, IF_NONE_MATCH, IF_MODIFIED_SINCE};
+
+use super::fetch::{write_atomically, sibling_pathname};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Validators
+{
+    pub url: String,
+    etag: Option<String>,
+    last_modified: Option<String>
+}
+
+impl Validators
+{
+    /* describe where a response came from, and its validators if the server provided any
+       => url = URL the response came from
+          headers = response's headers
+       <= returns the description */
+    pub fn new(url: &str, headers: &HeaderMap) -> Validators
+    {
+        let header = |name: HeaderName| headers.get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+        Validators { url: url.to_string(), etag: header(ETAG), last_modified: header(LAST_MODIFIED) }
+    }
+
+    /* pick out a response's validators
+       => url = URL the response came from
+          headers = response's headers
+       <= returns the validators, or None if the server didn't provide any */
+    pub fn from_headers(url: &str, headers: &HeaderMap) -> Option<Validators>
+    {
+        match Validators::new(url, headers)
+        {
+            Validators { etag: None, last_modified: None, .. } => None,
+            validators => Some(validators)
+        }
+    }
+
+    /* pick the validator to send in an If-Range header: a strong ETag, or else the Last-Modified date.
+       weak ETags can't be used to resume a download
+       <= returns the validator, or None if there isn't a usable one */
+    pub fn if_range(&self) -> Option<&String>
+    {
+        match &self.etag
+        {
+            Some(etag) if etag.starts_with("W/") == false => Some(etag),
+            _ => self.last_modified.as_ref()
+        }
+    }
+
+    /* load the validators kept for a guest, if any
+       => dest = pathname of the guest kernel image, or download in progress, they describe
+       <= returns the validators, or None if there aren't any or they can't be read */
+    pub fn load(dest: &Path) -> Option<Validators>
+    {
+        match read_to_string(validators_pathname(dest))
+        {
+            Ok(contents) => toml::from_str(contents.as_str()).ok(),
+            Err(_) => None
+        }
+    }
+
+    /* keep these validators for a guest
+       => dest = pathname of the guest kernel image, or download in progress, they describe
+       <= returns Ok on success, or an error message */
+    pub fn save(&self, dest: &Path) -> Result<(), String>
+    {
+        let path = validators_pathname(dest);
+        match toml::to_string(self)
+        {
+            Ok(contents) => write_atomically(&path, contents.as_bytes()),
+            Err(e) => Err(format!("Can't generate {}: {}", path.display(), e))
+        }
+    }
+
+    /* forget a guest's validators, if it has any */
+    pub fn remove(dest: &Path)
+    {
+        let _ = remove_file(validators_pathname(dest));
+    }
+
+    /* make a request conditional on the remote file having changed since these validators were issued */
+    pub fn apply(&self, request: RequestBuilder) -> RequestBuilder
+    {
+        let mut request = request;
+        if let Some(etag) = &self.etag
+        {
+            request = request.header(IF_NONE_MATCH, etag.as_str());
+        }
+        if let Some(last_modified) = &self.last_modified
+        {
+            request = request.header(IF_MODIFIED_SINCE, last_modified.as_str());
+        }
+        request
+    }
+}
+
+/* generate the pathname of the file holding dest's validators */
+fn validators_pathname(dest: &Path) -> PathBuf
+{
+    sibling_pathname(dest, "validators")
+}